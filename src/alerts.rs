@@ -0,0 +1,230 @@
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use log::*;
+use regex::Regex;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::ansi::strip;
+use crate::config::{WatcherAction, WatcherConfig};
+use crate::lines::{LineWriter, Lines};
+use crate::runner::Handle;
+
+/// A line of the command's output that a watcher matched
+pub struct Alert {
+    pub watcher: String,
+    pub command: String,
+    pub line: String,
+    /// Lines around the match, including it
+    pub context: String,
+    /// Matches since the last alert that weren't sent because of rate limiting
+    pub suppressed: usize,
+    /// Whether the command was killed because of the match
+    pub killed: bool,
+}
+
+impl Display for Alert {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Watcher \"{}\" matched: {}", self.watcher, self.line)?;
+        if self.suppressed > 0 {
+            write!(f, " ({} earlier matches not sent)", self.suppressed)?;
+        }
+        if self.killed {
+            f.write_str("\nThe command was killed")?;
+        }
+        Ok(())
+    }
+}
+
+struct Rule {
+    name: String,
+    regex: Regex,
+    context: usize,
+    min_interval: Duration,
+    action: WatcherAction,
+}
+
+#[derive(Default)]
+struct RateLimit {
+    last: Option<Instant>,
+    suppressed: usize,
+}
+
+/// Watchers shared by the stdout and stderr of one run
+#[derive(Clone)]
+pub struct Watchers {
+    rules: Arc<Vec<Rule>>,
+    limits: Arc<Mutex<Vec<RateLimit>>>,
+    alerts: UnboundedSender<Alert>,
+    command: String,
+    handle: Handle,
+}
+
+impl Watchers {
+    /// Compiles the patterns, returning the one that failed if any does
+    pub fn new(
+        config: &[WatcherConfig],
+        alerts: UnboundedSender<Alert>,
+        command: String,
+    ) -> Result<Self, (String, regex::Error)> {
+        let mut rules = Vec::new();
+        for watcher in config {
+            let regex = match Regex::new(&watcher.pattern) {
+                Ok(regex) => regex,
+                Err(err) => return Err((watcher.pattern.clone(), err)),
+            };
+            rules.push(Rule {
+                name: watcher.name.clone().unwrap_or(watcher.pattern.clone()),
+                regex,
+                context: watcher.context,
+                min_interval: Duration::from_secs_f64(watcher.min_interval),
+                action: watcher.action,
+            });
+        }
+        Ok(Self {
+            limits: Arc::new(Mutex::new(
+                rules.iter().map(|_| RateLimit::default()).collect(),
+            )),
+            rules: Arc::new(rules),
+            alerts,
+            command,
+            handle: Handle::default(),
+        })
+    }
+
+    /// Sets the process killed by the `kill` action
+    pub fn attach(&mut self, handle: Handle) {
+        self.handle = handle;
+    }
+
+    /// A writer checking one stream line by line
+    pub fn stream(&self) -> LineWriter<LineWatcher> {
+        let watcher = LineWatcher {
+            watchers: self.clone(),
+            before: VecDeque::new(),
+            pending: Vec::new(),
+        };
+        LineWriter::new(watcher, b"\n")
+    }
+}
+
+/// An alert still collecting the lines after its match
+struct Pending {
+    alert: Alert,
+    lines_left: usize,
+}
+
+pub struct LineWatcher {
+    watchers: Watchers,
+    /// The last lines seen, for the context before a match
+    before: VecDeque<String>,
+    pending: Vec<Pending>,
+}
+
+impl LineWatcher {
+    fn send(&self, alert: Alert) {
+        // The receiver is gone once the run is over, there's nobody to tell
+        let _ = self.watchers.alerts.send(alert);
+    }
+
+    fn check(&mut self, line: &[u8]) {
+        let line = String::from_utf8_lossy(line);
+        // Only the final state of a line redrawn with \r is of interest
+        let line = line.split('\r').rfind(|s| !s.is_empty()).unwrap_or("");
+        let line = strip(line);
+
+        for pending in &mut self.pending {
+            pending.alert.context.push_str(&line);
+            pending.alert.context.push('\n');
+            pending.lines_left -= 1;
+        }
+        let (done, pending): (Vec<Pending>, Vec<Pending>) =
+            self.pending.drain(..).partition(|p| p.lines_left == 0);
+        self.pending = pending;
+        for pending in done {
+            self.send(pending.alert);
+        }
+
+        let watchers = self.watchers.clone();
+        for (i, rule) in watchers.rules.iter().enumerate() {
+            if !rule.regex.is_match(&line) {
+                continue;
+            }
+            let suppressed = {
+                let mut limits = watchers.limits.lock().expect("watcher lock poisoned");
+                let limit = &mut limits[i];
+                if limit
+                    .last
+                    .is_some_and(|last| last.elapsed() < rule.min_interval)
+                {
+                    limit.suppressed += 1;
+                    continue;
+                }
+                limit.last = Some(Instant::now());
+                std::mem::take(&mut limit.suppressed)
+            };
+
+            let killed = rule.action == WatcherAction::Kill && watchers.handle.kill();
+            if killed {
+                info!("Watcher {} matched, killed the command", rule.name);
+            }
+            let start = self.before.len().saturating_sub(rule.context);
+            let mut context: String = self
+                .before
+                .range(start..)
+                .map(|line| format!("{}\n", line))
+                .collect();
+            context.push_str(&line);
+            context.push('\n');
+            let alert = Alert {
+                watcher: rule.name.clone(),
+                command: watchers.command.clone(),
+                line: line.clone(),
+                context,
+                suppressed,
+                killed,
+            };
+            if rule.context == 0 {
+                self.send(alert);
+            } else {
+                self.pending.push(Pending {
+                    alert,
+                    lines_left: rule.context,
+                });
+            }
+        }
+
+        let keep = self
+            .watchers
+            .rules
+            .iter()
+            .map(|r| r.context)
+            .max()
+            .unwrap_or(0);
+        self.before.push_back(line);
+        while self.before.len() > keep {
+            self.before.pop_front();
+        }
+    }
+}
+
+impl Lines for LineWatcher {
+    fn line(&mut self, line: &[u8], complete: bool) {
+        if complete {
+            self.check(line);
+        }
+    }
+}
+
+impl Drop for LineWatcher {
+    /// Sends the alerts still waiting for lines once the stream ends
+    fn drop(&mut self) {
+        for pending in std::mem::take(&mut self.pending) {
+            self.send(pending.alert);
+        }
+    }
+}
@@ -0,0 +1,271 @@
+/// Removes ANSI escape sequences (colors, cursor movement, titles) from `text`.
+pub fn strip(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            stripped.push(c);
+            continue;
+        }
+        match chars.next() {
+            // CSI, ends with a byte in @ to ~
+            Some('[') => {
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            // OSC, ends with BEL or ESC \
+            Some(']') => {
+                while let Some(c) = chars.next() {
+                    if c == '\x07' {
+                        break;
+                    }
+                    if c == '\x1b' && chars.peek() == Some(&'\\') {
+                        chars.next();
+                        break;
+                    }
+                }
+            }
+            // Charset selection like ESC ( B and other escapes with
+            // intermediate bytes, ends with a byte in 0 to ~
+            Some(' '..='/') => {
+                for c in chars.by_ref() {
+                    if ('0'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    stripped
+}
+
+/// Keeps only the final state of lines redrawn with carriage returns, as
+/// progress bars do, and drops the `\r` a PTY adds before each newline.
+pub fn collapse_redraws(text: &str) -> String {
+    text.split('\n')
+        .map(|line| {
+            line.split('\r')
+                .rfind(|segment| !strip(segment).is_empty())
+                .unwrap_or("")
+        })
+        .collect::<Vec<&str>>()
+        .join("\n")
+}
+
+const PALETTE: [&str; 16] = [
+    "#000000", "#cd0000", "#00cd00", "#cdcd00", "#0000ee", "#cd00cd", "#00cdcd", "#e5e5e5",
+    "#7f7f7f", "#ff0000", "#00ff00", "#ffff00", "#5c5cff", "#ff00ff", "#00ffff", "#ffffff",
+];
+
+#[derive(Clone, Default, PartialEq)]
+struct Style {
+    fg: Option<String>,
+    bg: Option<String>,
+    bold: bool,
+    italic: bool,
+    underline: bool,
+}
+
+impl Style {
+    fn is_plain(&self) -> bool {
+        *self == Style::default()
+    }
+
+    /// Applies the parameters of an SGR (`ESC [ ... m`) sequence
+    fn apply(&mut self, params: &str) {
+        let mut params = params.split(';').map(|p| p.parse::<u8>().unwrap_or(0));
+        while let Some(param) = params.next() {
+            match param {
+                0 => *self = Style::default(),
+                1 => self.bold = true,
+                3 => self.italic = true,
+                4 => self.underline = true,
+                22 => self.bold = false,
+                23 => self.italic = false,
+                24 => self.underline = false,
+                30..=37 => self.fg = Some(PALETTE[(param - 30) as usize].to_owned()),
+                90..=97 => self.fg = Some(PALETTE[(param - 90 + 8) as usize].to_owned()),
+                40..=47 => self.bg = Some(PALETTE[(param - 40) as usize].to_owned()),
+                100..=107 => self.bg = Some(PALETTE[(param - 100 + 8) as usize].to_owned()),
+                39 => self.fg = None,
+                49 => self.bg = None,
+                38 | 48 => {
+                    let color = match params.next() {
+                        Some(5) => params.next().map(color_256),
+                        Some(2) => match (params.next(), params.next(), params.next()) {
+                            (Some(r), Some(g), Some(b)) => {
+                                Some(format!("#{:02x}{:02x}{:02x}", r, g, b))
+                            }
+                            _ => None,
+                        },
+                        _ => None,
+                    };
+                    if param == 38 {
+                        self.fg = color;
+                    } else {
+                        self.bg = color;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn open(&self, html: &mut String) {
+        let mut attributes = String::new();
+        let mut css = String::new();
+        // Matrix clients only keep the data-mx attributes, email clients only the style
+        if let Some(fg) = &self.fg {
+            attributes += &format!(" data-mx-color=\"{}\"", fg);
+            css += &format!("color: {};", fg);
+        }
+        if let Some(bg) = &self.bg {
+            attributes += &format!(" data-mx-bg-color=\"{}\"", bg);
+            css += &format!("background-color: {};", bg);
+        }
+        if !css.is_empty() {
+            html.push_str(&format!("<span{} style=\"{}\">", attributes, css));
+        }
+        if self.bold {
+            html.push_str("<b>");
+        }
+        if self.italic {
+            html.push_str("<i>");
+        }
+        if self.underline {
+            html.push_str("<u>");
+        }
+    }
+
+    fn close(&self, html: &mut String) {
+        if self.underline {
+            html.push_str("</u>");
+        }
+        if self.italic {
+            html.push_str("</i>");
+        }
+        if self.bold {
+            html.push_str("</b>");
+        }
+        if self.fg.is_some() || self.bg.is_some() {
+            html.push_str("</span>");
+        }
+    }
+}
+
+fn color_256(n: u8) -> String {
+    match n {
+        0..=15 => PALETTE[n as usize].to_owned(),
+        16..=231 => {
+            let level = |v: u8| if v == 0 { 0 } else { 55 + v * 40 };
+            let n = n - 16;
+            format!(
+                "#{:02x}{:02x}{:02x}",
+                level(n / 36),
+                level((n / 6) % 6),
+                level(n % 6)
+            )
+        }
+        232..=255 => {
+            let grey = 8 + (n - 232) * 10;
+            format!("#{:02x}{:02x}{:02x}", grey, grey, grey)
+        }
+    }
+}
+
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Converts text containing ANSI colors into HTML, other escape sequences are
+/// dropped. The result is meant to go inside a `<pre>`.
+pub fn to_html(text: &str) -> String {
+    let mut html = String::with_capacity(text.len());
+    let mut style = Style::default();
+    let mut chars = text.chars().peekable();
+    let mut run = String::new();
+
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            run.push(c);
+            continue;
+        }
+
+        let mut sequence = String::new();
+        let mut is_sgr = false;
+        match chars.next() {
+            Some('[') => {
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        is_sgr = c == 'm';
+                        break;
+                    }
+                    sequence.push(c);
+                }
+            }
+            Some(']') => {
+                while let Some(c) = chars.next() {
+                    if c == '\x07' {
+                        break;
+                    }
+                    if c == '\x1b' && chars.peek() == Some(&'\\') {
+                        chars.next();
+                        break;
+                    }
+                }
+            }
+            Some(' '..='/') => {
+                for c in chars.by_ref() {
+                    if ('0'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+        if !is_sgr {
+            continue;
+        }
+
+        let mut next = style.clone();
+        next.apply(&sequence);
+        if next == style {
+            continue;
+        }
+        push_run(&mut html, &style, &run);
+        run.clear();
+        style = next;
+    }
+    push_run(&mut html, &style, &run);
+
+    html
+}
+
+fn push_run(html: &mut String, style: &Style, run: &str) {
+    if run.is_empty() {
+        return;
+    }
+    if style.is_plain() {
+        html.push_str(&escape_html(run));
+    } else {
+        style.open(html);
+        html.push_str(&escape_html(run));
+        style.close(html);
+    }
+}
@@ -0,0 +1,144 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use glob::glob;
+use lazy_static::lazy_static;
+use log::*;
+use mime::Mime;
+
+lazy_static! {
+    static ref INFER: infer::Infer = {
+        let mut infer = infer::Infer::new();
+        infer.add("application/vnd.apache.parquet", "parquet", is_parquet);
+        infer
+    };
+}
+
+pub struct Artifacts {
+    pub found: Vec<PathBuf>,
+    /// Patterns that didn't produce a file, with the reason why
+    pub missing: Vec<(String, String)>,
+}
+
+impl Display for Artifacts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Missing artifacts:")?;
+        for (pattern, reason) in &self.missing {
+            writeln!(f, "  {} ({})", pattern, reason)?;
+        }
+        Ok(())
+    }
+}
+
+/// Expands each glob pattern into the files it matches.
+///
+/// When `since` is set, files last modified before it are skipped so only
+/// artifacts written by the current run are collected.
+pub fn collect(patterns: &[String], since: Option<SystemTime>) -> Artifacts {
+    let mut artifacts = Artifacts {
+        found: Vec::new(),
+        missing: Vec::new(),
+    };
+
+    for pattern in patterns {
+        let paths = match glob(pattern) {
+            Ok(paths) => paths,
+            Err(err) => {
+                artifacts
+                    .missing
+                    .push((pattern.to_owned(), format!("invalid pattern: {}", err)));
+                continue;
+            }
+        };
+
+        let mut matched = false;
+        let mut stale = false;
+        for path in paths {
+            let path = match path {
+                Ok(path) => path,
+                Err(err) => {
+                    warn!("Can't read {}: {}", err.path().display(), err);
+                    continue;
+                }
+            };
+            let modified = match fs::metadata(&path) {
+                Ok(metadata) if metadata.is_file() => metadata.modified().ok(),
+                _ => continue,
+            };
+            if let (Some(since), Some(modified)) = (since, modified) {
+                if modified < since {
+                    debug!("Skipping {}, not modified during the run", path.display());
+                    stale = true;
+                    continue;
+                }
+            }
+            matched = true;
+            if !artifacts.found.contains(&path) {
+                artifacts.found.push(path);
+            }
+        }
+
+        if !matched {
+            let reason = if stale {
+                "not modified since the run started"
+            } else {
+                "no matching files"
+            };
+            artifacts
+                .missing
+                .push((pattern.to_owned(), reason.to_owned()));
+        }
+    }
+
+    artifacts
+}
+
+fn is_parquet(buf: &[u8]) -> bool {
+    buf.starts_with(b"PAR1")
+}
+
+/// Works out the MIME type of a file from its contents.
+///
+/// A type configured for the extension always wins, then the magic bytes are
+/// checked, then the extension is looked up. Anything left is sent as plain
+/// text when it looks like text and as an octet stream otherwise.
+pub fn detect_mime(path: &Path, data: &[u8], overrides: &HashMap<String, String>) -> Mime {
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase());
+
+    if let Some(configured) = extension.as_ref().and_then(|ext| overrides.get(ext)) {
+        match configured.parse() {
+            Ok(mime) => return mime,
+            Err(_) => warn!("Ignoring invalid MIME type {} in config", configured),
+        }
+    }
+
+    if let Some(kind) = INFER.get(data) {
+        if let Ok(mime) = kind.mime_type().parse() {
+            return mime;
+        }
+    }
+
+    if let Some(mime) = mime_guess::from_path(path).first() {
+        return mime;
+    }
+
+    let head = &data[..data.len().min(8192)];
+    // A character cut in half at the end of the head is still text
+    let utf8 = match std::str::from_utf8(head) {
+        Ok(_) => true,
+        Err(err) => err.error_len().is_none(),
+    };
+    if !head.contains(&0) && utf8 {
+        mime::TEXT_PLAIN
+    } else {
+        mime::APPLICATION_OCTET_STREAM
+    }
+}
@@ -1,10 +1,17 @@
-use std::{fmt::Display, str::FromStr};
+use std::{collections::VecDeque, fmt::Display, str::FromStr};
 
 use async_trait::async_trait;
+use chrono::{DateTime, Local};
 use mime::Mime;
 use thiserror::Error;
+use tokio::sync::mpsc;
 
+use crate::alerts::Alert;
+use crate::config::OutputLimits;
+use crate::diff::RunDiff;
 use crate::runner::CommandInfo;
+use crate::schedule::parse_when;
+use crate::table::Table;
 
 #[derive(Error, Debug)]
 pub enum BackendError {
@@ -29,25 +36,183 @@ impl Display for ParseError {
 
 pub enum Sendable {
     Raw(String),
-    CommandInfo(CommandInfo),
+    CommandInfo(Box<CommandInfo>),
+    Alert(Alert),
+    Table(Table),
+    Diff(RunDiff),
     Image((Mime, String, Vec<u8>)),
     File((Mime, String, Vec<u8>)),
 }
 
-#[derive(PartialEq)]
+impl Sendable {
+    /// Sends images as images and everything else as a file
+    pub fn attachment(mime: Mime, name: String, data: Vec<u8>) -> Self {
+        if mime.type_() == mime::IMAGE {
+            Sendable::Image((mime, name, data))
+        } else {
+            Sendable::File((mime, name, data))
+        }
+    }
+}
+
+#[derive(PartialEq, Debug)]
 pub enum BackendCommand {
     Rerun,
     Done,
     UnkownCommand(String),
     Cat,
+    /// Attachments sent by the user as (file name, contents)
+    Upload(Vec<(String, Vec<u8>)>),
+    /// An attachment that wasn't downloaded, as (file name, reason)
+    UploadRejected(String, String),
+    /// Rerun once at the given time
+    RerunAt(DateTime<Local>),
+    /// List the scheduled runs
+    Schedule,
+    /// Cancel a scheduled run, or all of them
+    Cancel(Option<u32>),
+    /// How far along the running command is
+    Progress,
+    /// List the last runs
+    History(usize),
+    /// Details of a run from the history
+    Show(u32),
+    /// Compare the output of two runs, by default the last two
+    Diff(Option<u32>, Option<u32>),
+    /// Run the command this many times and report statistics
+    Bench(usize),
+    /// Run the command over the parameter sweep from the config
+    Sweep,
+    /// Rerun a named job, or all of them
+    RerunJob(String),
+    /// What a named job is doing, or all of them
+    Status(Option<String>),
+    /// Kill a named job, or all of them
+    Kill(String),
+}
+
+impl BackendCommand {
+    /// Parses a command sent as text
+    pub fn parse(text: &str) -> Self {
+        let command = text.trim().to_ascii_lowercase();
+        let (word, rest) = command.split_once(' ').unwrap_or((&command, ""));
+        let parsed = match (word, rest.trim()) {
+            ("rerun", "") => Some(BackendCommand::Rerun),
+            ("rerun", rest) => parse_when(rest)
+                .map(BackendCommand::RerunAt)
+                .or_else(|| job_name(rest).map(BackendCommand::RerunJob)),
+            ("done", "") => Some(BackendCommand::Done),
+            ("cat", "") => Some(BackendCommand::Cat),
+            ("schedule", "") => Some(BackendCommand::Schedule),
+            ("progress", "") => Some(BackendCommand::Progress),
+            ("status", "") => Some(BackendCommand::Status(None)),
+            ("progress", name) | ("status", name) => {
+                job_name(name).map(|name| BackendCommand::Status(Some(name)))
+            }
+            ("kill", name) => job_name(name).map(BackendCommand::Kill),
+            ("history", "") => Some(BackendCommand::History(10)),
+            ("history", count) => count.parse().ok().map(BackendCommand::History),
+            ("diff", runs) => parse_diff(runs),
+            ("sweep", "") => Some(BackendCommand::Sweep),
+            ("bench", "") => Some(BackendCommand::Bench(10)),
+            ("bench", runs) => runs
+                .parse()
+                .ok()
+                .filter(|runs| *runs > 0)
+                .map(BackendCommand::Bench),
+            ("show", id) => id.parse().ok().map(BackendCommand::Show),
+            ("cancel", "all") => Some(BackendCommand::Cancel(None)),
+            ("cancel", id) => id.parse().ok().map(|id| BackendCommand::Cancel(Some(id))),
+            _ => None,
+        };
+        parsed.unwrap_or_else(|| BackendCommand::UnkownCommand(text.to_owned()))
+    }
+}
+
+/// A job name is a single word
+fn job_name(name: &str) -> Option<String> {
+    match name.split_whitespace().count() {
+        1 => Some(name.to_owned()),
+        _ => None,
+    }
+}
+
+/// Parses the runs of `diff [a] [b]`
+fn parse_diff(runs: &str) -> Option<BackendCommand> {
+    let ids: Vec<&str> = runs.split_whitespace().collect();
+    match ids[..] {
+        [] => Some(BackendCommand::Diff(None, None)),
+        [a] => Some(BackendCommand::Diff(Some(a.parse().ok()?), None)),
+        [a, b] => Some(BackendCommand::Diff(
+            Some(a.parse().ok()?),
+            Some(b.parse().ok()?),
+        )),
+        _ => None,
+    }
 }
 
 #[async_trait]
 pub trait Backend {
     async fn send_text(&mut self, info: &Sendable) -> Result<(), BackendError>;
+    /// The next command from the user, safe to use in `select!`
+    async fn recieve(&mut self) -> Result<BackendCommand, BackendError>;
+    /// Puts a command off until the running command is done
+    fn defer(&mut self, command: BackendCommand);
+    /// The oldest command put off, if any
+    fn deferred(&mut self) -> Option<BackendCommand>;
+    fn limits(&self) -> &OutputLimits;
+}
+
+/// Reads commands from the user for a backend
+#[async_trait]
+pub trait Receiver: Send {
     async fn recieve(&mut self) -> Result<BackendCommand, BackendError>;
 }
 
+/// Commands from the user, read by a task of their own so that a read is
+/// never dropped half way through when something else happens first
+pub struct Commands {
+    commands: mpsc::UnboundedReceiver<Result<BackendCommand, BackendError>>,
+    deferred: VecDeque<BackendCommand>,
+}
+
+impl Commands {
+    pub fn listen(mut receiver: Box<dyn Receiver>) -> Self {
+        let (sender, commands) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let command = receiver.recieve().await;
+                let failed = command.is_err();
+                if sender.send(command).is_err() || failed {
+                    break;
+                }
+            }
+        });
+        Self {
+            commands,
+            deferred: VecDeque::new(),
+        }
+    }
+
+    /// The next command the user sent, waiting drops nothing
+    pub async fn recv(&mut self) -> Result<BackendCommand, BackendError> {
+        match self.commands.recv().await {
+            Some(command) => command,
+            None => Err(BackendError::Unknown(
+                "Stopped receiving commands".to_owned(),
+            )),
+        }
+    }
+
+    pub fn defer(&mut self, command: BackendCommand) {
+        self.deferred.push_back(command);
+    }
+
+    pub fn deferred(&mut self) -> Option<BackendCommand> {
+        self.deferred.pop_front()
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum BackendList {
     Email,
@@ -65,3 +230,67 @@ impl FromStr for BackendList {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> BackendCommand {
+        BackendCommand::parse(text)
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(parse("rerun"), BackendCommand::Rerun);
+        assert_eq!(parse("  Done \n"), BackendCommand::Done);
+        assert_eq!(parse("progress"), BackendCommand::Progress);
+        assert_eq!(parse("sweep"), BackendCommand::Sweep);
+        assert_eq!(parse("history"), BackendCommand::History(10));
+        assert_eq!(parse("history 5"), BackendCommand::History(5));
+        assert_eq!(parse("show 4"), BackendCommand::Show(4));
+        assert_eq!(parse("bench"), BackendCommand::Bench(10));
+        assert_eq!(parse("bench 3"), BackendCommand::Bench(3));
+        assert_eq!(parse("cancel all"), BackendCommand::Cancel(None));
+        assert_eq!(parse("cancel 2"), BackendCommand::Cancel(Some(2)));
+        assert!(matches!(parse("rerun in 2h"), BackendCommand::RerunAt(_)));
+    }
+
+    #[test]
+    fn parses_job_commands() {
+        assert_eq!(
+            parse("Rerun Train"),
+            BackendCommand::RerunJob("train".to_owned())
+        );
+        assert_eq!(
+            parse("status eval"),
+            BackendCommand::Status(Some("eval".to_owned()))
+        );
+        assert_eq!(
+            parse("progress eval"),
+            BackendCommand::Status(Some("eval".to_owned()))
+        );
+        assert_eq!(parse("kill all"), BackendCommand::Kill("all".to_owned()));
+    }
+
+    #[test]
+    fn parses_diff() {
+        assert_eq!(parse("diff"), BackendCommand::Diff(None, None));
+        assert_eq!(parse("diff 3"), BackendCommand::Diff(Some(3), None));
+        assert_eq!(parse("diff 3 4"), BackendCommand::Diff(Some(3), Some(4)));
+    }
+
+    #[test]
+    fn keeps_unknown_commands() {
+        for text in [
+            "",
+            "hello",
+            "bench 0",
+            "history x",
+            "diff 1 2 3",
+            "kill two jobs",
+            "rerun in 99999999999999999d",
+        ] {
+            assert_eq!(parse(text), BackendCommand::UnkownCommand(text.to_owned()));
+        }
+    }
+}
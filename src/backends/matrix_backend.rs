@@ -4,16 +4,13 @@ use log::*;
 use matrix_sdk::{
     attachment::AttachmentConfig,
     config::SyncSettings,
+    media::MediaEventContent,
     room::{Joined, Room},
     ruma::{
-        events::{
-            room::message::{
-                ImageMessageEventContent, MessageType, OriginalSyncRoomMessageEvent,
-                RoomMessageEventContent,
-            },
-            MessageLikeEvent, MessageLikeEventContent,
+        events::room::message::{
+            MessageType, OriginalSyncRoomMessageEvent, RoomMessageEventContent,
         },
-        OwnedUserId, RoomId, UserId,
+        RoomId, UserId,
     },
     Client, Error,
 };
@@ -22,24 +19,37 @@ use tokio::{
     task::JoinHandle,
 };
 
-use super::backend::{Backend, BackendCommand, BackendError, Sendable};
-use crate::config::MatrixConfig;
+use super::backend::{Backend, BackendCommand, BackendError, Commands, Receiver, Sendable};
+use crate::ansi::{escape_html, strip, to_html};
+use crate::config::{MatrixConfig, OutputLimits};
+use crate::output::{excerpt, invalid_note, status_line};
 
 lazy_static! {
-    static ref MESSAGES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+    static ref MESSAGES: Mutex<Vec<MessageType>> = Mutex::new(Vec::new());
     static ref NOTIFY: Notify = Notify::new();
 }
 
-static mut ADDRESS: Option<OwnedUserId> = None;
-
 pub struct MatrixBackend {
     config: MatrixConfig,
-    handle: JoinHandle<Result<(), Error>>,
+    /// The sync loop, running for as long as the backend is
+    _sync: JoinHandle<Result<(), Error>>,
     room: Joined,
+    commands: Commands,
+}
+
+/// Reads the commands the sync loop picks up, downloading any uploads
+struct MatrixReceiver {
+    client: Client,
+    /// Largest upload accepted, files over it aren't downloaded and none are
+    /// when uploads are disabled
+    max_upload: Option<usize>,
 }
 
 impl MatrixBackend {
-    pub async fn new(config: MatrixConfig) -> Result<Self, BackendError> {
+    pub async fn new(
+        config: MatrixConfig,
+        max_upload: Option<usize>,
+    ) -> Result<Self, BackendError> {
         let user = UserId::parse(&config.username).unwrap();
         let address = UserId::parse(&config.address).unwrap();
         let client = Client::builder()
@@ -57,73 +67,168 @@ impl MatrixBackend {
 
         client.sync_once(SyncSettings::default()).await.unwrap();
 
-        unsafe {
-            ADDRESS = Some(address);
-        }
-
         let room = client
             .get_joined_room(<&RoomId>::try_from(config.room.as_str()).unwrap())
             .unwrap();
 
+        let sync_client = client.clone();
         let handle = tokio::spawn(async move {
-            client.add_event_handler(|ev: OriginalSyncRoomMessageEvent, room: Room| async move {
-                let Room::Joined(room) = room else { panic!("Didn't join room") };
-                let MessageType::Text(text_content) = ev.content.msgtype else { return };
-
-                info!("Got message");
-                let mut msgs = MESSAGES.lock().await;
-                trace!("Obtained MESSAGES lock");
-                unsafe {
-                    if ev.sender != ADDRESS.clone().unwrap() {
+            sync_client.add_event_handler(move |ev: OriginalSyncRoomMessageEvent, room: Room| {
+                let address = address.clone();
+                async move {
+                    let Room::Joined(_) = room else {
+                        panic!("Didn't join room")
+                    };
+                    match ev.content.msgtype {
+                        MessageType::Text(_) | MessageType::File(_) | MessageType::Image(_) => {}
+                        _ => return,
+                    }
+
+                    info!("Got message");
+                    let mut msgs = MESSAGES.lock().await;
+                    trace!("Obtained MESSAGES lock");
+                    if ev.sender != address {
                         warn!("Got message from wrong sender: {}", ev.sender);
                         return;
                     }
+                    msgs.push(ev.content.msgtype);
+                    NOTIFY.notify_one();
+                    trace!("Sent notification");
                 }
-                msgs.push(text_content.body);
-                NOTIFY.notify_one();
-                trace!("Sent notification");
-                return;
             });
-            client.sync(SyncSettings::default()).await
+            sync_client.sync(SyncSettings::default()).await
         });
 
+        let receiver = MatrixReceiver { client, max_upload };
         Ok(MatrixBackend {
             config,
-            handle,
+            _sync: handle,
             room,
+            commands: Commands::listen(Box::new(receiver)),
         })
     }
 }
 
+impl MatrixReceiver {
+    async fn download(
+        &self,
+        name: String,
+        size: Option<u64>,
+        content: impl MediaEventContent,
+    ) -> BackendCommand {
+        let Some(max) = self.max_upload else {
+            info!("Not downloading {}, uploads are disabled", name);
+            let reason = "uploads are disabled, add an upload section to the config";
+            return BackendCommand::UploadRejected(name, reason.to_owned());
+        };
+        if let Some(size) = size {
+            if size > max as u64 {
+                info!("Not downloading {} ({} bytes)", name, size);
+                let reason = format!("{} bytes is over the {} byte limit", size, max);
+                return BackendCommand::UploadRejected(name, reason);
+            }
+        }
+        // A failed download is only that upload's problem, not the backend's
+        match self.client.media().get_file(content, false).await {
+            Ok(Some(data)) => {
+                info!("Downloaded {} ({} bytes)", name, data.len());
+                BackendCommand::Upload(vec![(name, data)])
+            }
+            Ok(None) => BackendCommand::UploadRejected(name, "it has no media source".to_owned()),
+            Err(err) => {
+                error!("Failed to download {} with:\n{}", name, err);
+                BackendCommand::UploadRejected(name, format!("failed to download it: {}", err))
+            }
+        }
+    }
+}
+
 #[async_trait]
-impl Backend for MatrixBackend {
+impl Receiver for MatrixReceiver {
     async fn recieve(&mut self) -> Result<BackendCommand, BackendError> {
         trace!("Preparing to recieve");
         NOTIFY.notified().await;
         trace!("Notification recieved");
-        let mut msgs = MESSAGES.lock().await;
-        trace!("Lock recieved");
-        let message = msgs.pop().unwrap();
-
-        match message.as_str() {
-            "rerun" => Ok(BackendCommand::Rerun),
-            "done" => Ok(BackendCommand::Done),
-            "cat" => Ok(BackendCommand::Cat),
-            _ => Ok(BackendCommand::UnkownCommand(message)),
+        let message = {
+            let mut msgs = MESSAGES.lock().await;
+            trace!("Lock recieved");
+            msgs.pop().unwrap()
+        };
+
+        match message {
+            MessageType::Text(text) => Ok(BackendCommand::parse(&text.body)),
+            MessageType::File(file) => {
+                let name = file.filename.clone().unwrap_or_else(|| file.body.clone());
+                let size = file.info.as_ref().and_then(|info| info.size);
+                Ok(self.download(name, size.map(u64::from), file).await)
+            }
+            MessageType::Image(image) => {
+                let size = image.info.as_ref().and_then(|info| info.size);
+                Ok(self
+                    .download(image.body.clone(), size.map(u64::from), image)
+                    .await)
+            }
+            other => Ok(BackendCommand::UnkownCommand(other.body().to_owned())),
         }
     }
+}
+
+#[async_trait]
+impl Backend for MatrixBackend {
+    async fn recieve(&mut self) -> Result<BackendCommand, BackendError> {
+        self.commands.recv().await
+    }
+
+    fn defer(&mut self, command: BackendCommand) {
+        self.commands.defer(command);
+    }
+
+    fn deferred(&mut self) -> Option<BackendCommand> {
+        self.commands.deferred()
+    }
 
     async fn send_text(&mut self, msg: &Sendable) -> Result<(), BackendError> {
         match msg {
             Sendable::CommandInfo(info) => {
-                let content =
-                    RoomMessageEventContent::text_markdown(
-                        format!(
-                            "Ran command *{}* in *{}*s \n\n **STANDARD OUT:**\n\n{}\n\n**STANDARD ERROR:**\n\n{}",
-                            info.command,
-                            info.time.as_secs(),
-                            info.stdout,
-                            info.stderr));
+                let limits = &self.config.limits;
+                let plain = format!(
+                    "Ran command {} in {}s\n{}\n\nSTANDARD OUT{}:\n{}\n\nSTANDARD ERROR{}:\n{}",
+                    info.command,
+                    info.time.as_secs(),
+                    status_line(info),
+                    invalid_note(info.stdout_invalid),
+                    excerpt(&strip(&info.stdout), info.stdout_dropped, limits),
+                    invalid_note(info.stderr_invalid),
+                    excerpt(&strip(&info.stderr), info.stderr_dropped, limits));
+                let html = format!(
+                    "<p>Ran command <em>{}</em> in <em>{}</em>s<br>{}</p><p><strong>STANDARD OUT{}:</strong></p><pre>{}</pre><p><strong>STANDARD ERROR{}:</strong></p><pre>{}</pre>",
+                    escape_html(&info.command),
+                    info.time.as_secs(),
+                    status_line(info),
+                    invalid_note(info.stdout_invalid),
+                    to_html(&excerpt(&info.stdout, info.stdout_dropped, limits)),
+                    invalid_note(info.stderr_invalid),
+                    to_html(&excerpt(&info.stderr, info.stderr_dropped, limits)));
+                let content = RoomMessageEventContent::text_html(plain, html);
+                self.room.send(content, None).await
+            }
+            Sendable::Alert(alert) => {
+                let plain = format!("{}\n\n{}", alert, alert.context);
+                let html = format!(
+                    "<p><strong>Alert from <em>{}</em></strong><br>{}</p><pre>{}</pre>",
+                    escape_html(&alert.command),
+                    escape_html(&alert.to_string()).replace('\n', "<br>"),
+                    escape_html(&alert.context));
+                let content = RoomMessageEventContent::text_html(plain, html);
+                self.room.send(content, None).await
+            }
+            Sendable::Table(table) => {
+                let plain = format!("{}\n{}", table.name, table.to_text());
+                let content = RoomMessageEventContent::text_html(plain, table.to_html());
+                self.room.send(content, None).await
+            }
+            Sendable::Diff(diff) => {
+                let content = RoomMessageEventContent::text_html(diff.to_string(), diff.to_html());
                 self.room.send(content, None).await
             }
             Sendable::Raw(s) => {
@@ -136,10 +241,11 @@ impl Backend for MatrixBackend {
             Sendable::File((mime, name,  data)) => {
                 self.room.send_attachment(name, mime, data, AttachmentConfig::new()).await
             }
-            _ => {
-                unimplemented!()
-            }
         }.unwrap();
         Ok(())
     }
+
+    fn limits(&self) -> &OutputLimits {
+        &self.config.limits
+    }
 }
@@ -1,4 +1,4 @@
-use std::time::Duration;
+use std::{path::Path, time::Duration};
 
 use async_imap::{
     error,
@@ -9,21 +9,40 @@ use async_native_tls;
 use async_native_tls::TlsStream;
 use async_trait::async_trait;
 use futures_util::TryStreamExt;
+use lazy_static::lazy_static;
 use lettre::{
-    transport::smtp::authentication::Credentials, AsyncSmtpTransport, AsyncTransport, Message,
-    Tokio1Executor,
+    message::{header::ContentType, Attachment, MultiPart, SinglePart},
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
 };
 use log::*;
+use mail_parser::MimeHeaders;
+use mime::Mime;
 use regex::Regex;
 use tokio::net::TcpStream;
 use tokio::time::sleep;
 
-use super::backend::{Backend, BackendCommand, BackendError, Sendable};
-use crate::config::EmailConfig;
+use super::backend::{Backend, BackendCommand, BackendError, Commands, Receiver, Sendable};
+use crate::ansi::{strip, to_html};
+use crate::config::{EmailConfig, OutputLimits};
+use crate::output::{deviation_note, excerpt, invalid_note, status_line};
+
+lazy_static! {
+    /// The "On <date> at <time> <someone> wrote:" line above a quoted reply
+    static ref QUOTE_HEADER: Regex =
+        Regex::new(r"^On.+ at .+wrote:").expect("Impossible error, failed to parse regex");
+}
 
 pub struct SmtpEmailBackend {
     config: EmailConfig,
     smtp: AsyncSmtpTransport<Tokio1Executor>,
+    commands: Commands,
+}
+
+/// Reads commands from the inbox
+struct ImapReceiver {
+    /// Only emails from this address are read
+    address: String,
     imap: Session<TlsStream<TcpStream>>,
 }
 
@@ -114,25 +133,29 @@ impl SmtpEmailBackend {
         };
 
         for msg in old {
-            match delete_message(msg, &mut imap).await {
-                Err(e) => error!("Failed to delete message {} with:\n{}", msg, e.to_string()),
-                Ok(_) => {}
-            };
+            if let Err(e) = delete_message(msg, &mut imap).await {
+                error!("Failed to delete message {} with:\n{}", msg, e.to_string());
+            }
         }
 
-        Ok(SmtpEmailBackend { config, smtp, imap })
+        let receiver = ImapReceiver {
+            address: config.address.clone(),
+            imap,
+        };
+        Ok(SmtpEmailBackend {
+            config,
+            smtp,
+            commands: Commands::listen(Box::new(receiver)),
+        })
     }
 }
 
 #[async_trait]
-impl Backend for SmtpEmailBackend {
+impl Receiver for ImapReceiver {
     async fn recieve(&mut self) -> Result<BackendCommand, BackendError> {
         // TODO: stop loop
         let messages = loop {
-            let new = self
-                .imap
-                .search(format!("FROM {}", self.config.address))
-                .await;
+            let new = self.imap.search(format!("FROM {}", self.address)).await;
 
             let new = match new {
                 Ok(new) => new,
@@ -174,71 +197,165 @@ impl Backend for SmtpEmailBackend {
             }
         };
 
-        for msg in msg.iter() {
-            let msg = match msg.body() {
-                Some(msg) => msg,
-                None => {
-                    return Err(BackendError::Unknown(
-                        "Failed to get email body with".to_string(),
-                    ))
-                }
-            };
-            let msg = match mail_parser::Message::parse(msg) {
-                Some(msg) => msg,
-                None => return Err(BackendError::Unknown("Failed to parse email".to_string())),
-            };
-            let body = match msg.body_text(0) {
-                Some(body) => body,
-                None => {
-                    return Err(BackendError::Unknown(
-                        "Failed to parse email body".to_string(),
-                    ))
-                }
-            };
+        let msg = match msg.first() {
+            Some(msg) => msg,
+            None => {
+                return Ok(BackendCommand::UnkownCommand(
+                    "Could not find command".to_owned(),
+                ))
+            }
+        };
+        let msg = match msg.body() {
+            Some(msg) => msg,
+            None => {
+                return Err(BackendError::Unknown(
+                    "Failed to get email body with".to_string(),
+                ))
+            }
+        };
+        let msg = match mail_parser::Message::parse(msg) {
+            Some(msg) => msg,
+            None => return Err(BackendError::Unknown("Failed to parse email".to_string())),
+        };
 
-            trace!("Body:\n{}", body);
-            let regex =
-                Regex::new(r"^On.+ at .+wrote:").expect("Impossible error, failed to parse regex");
-            let body: Vec<&str> = body
-                .split('\n')
-                .filter(|line| !line.is_empty())
-                .filter(|line| !line.starts_with('>'))
-                .filter(|line| !regex.is_match(line))
-                .collect();
-            if body.len() != 1 {
-                panic!("Bad length")
+        let body = msg.body_text(0);
+        let lines: Vec<&str> = match &body {
+            Some(body) => {
+                trace!("Body:\n{}", body);
+                body.split('\n')
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty())
+                    .filter(|line| !line.starts_with('>'))
+                    .filter(|line| !QUOTE_HEADER.is_match(line))
+                    .collect()
             }
-            let command = body[0];
+            None => Vec::new(),
+        };
+
+        // Inline parts are signature logos and the like, not uploads
+        let attachments: Vec<(String, Vec<u8>)> = msg
+            .attachments()
+            .filter(|part| !part.content_disposition().is_some_and(|cd| cd.is_inline()))
+            .map(|part| {
+                (
+                    part.attachment_name().unwrap_or("attachment").to_owned(),
+                    part.contents().to_vec(),
+                )
+            })
+            .collect();
+        // Attachments carried over in a reply with a command aren't uploads
+        let upload = match lines[..] {
+            [] => true,
+            [line] => line.eq_ignore_ascii_case("upload"),
+            _ => false,
+        };
+        if upload && !attachments.is_empty() {
+            info!("Got {} attachments", attachments.len());
             delete_message(*msg_id, &mut self.imap).await.unwrap();
-            return match command.to_ascii_lowercase().as_str() {
-                "rerun" => Ok(BackendCommand::Rerun),
-                "done" => Ok(BackendCommand::Done),
-                _ => Ok(BackendCommand::UnkownCommand(command.to_owned())),
-            };
+            return Ok(BackendCommand::Upload(attachments));
+        }
+
+        if body.is_none() {
+            return Err(BackendError::Unknown(
+                "Failed to parse email body".to_string(),
+            ));
+        }
+        if lines.len() != 1 {
+            panic!("Bad length")
         }
-        Ok(BackendCommand::UnkownCommand(
-            "Could not find command".to_owned(),
-        ))
+        let command = lines[0];
+        delete_message(*msg_id, &mut self.imap).await.unwrap();
+        Ok(BackendCommand::parse(command))
+    }
+}
+
+#[async_trait]
+impl Backend for SmtpEmailBackend {
+    async fn recieve(&mut self) -> Result<BackendCommand, BackendError> {
+        self.commands.recv().await
+    }
+
+    fn defer(&mut self, command: BackendCommand) {
+        self.commands.defer(command);
+    }
+
+    fn deferred(&mut self) -> Option<BackendCommand> {
+        self.commands.deferred()
     }
 
     async fn send_text(&mut self, info: &Sendable) -> Result<(), BackendError> {
         let subject = match info {
             Sendable::CommandInfo(info) => {
                 format!(
-                    "Command \"{}\" finished in {}",
+                    "Command \"{}\" finished in {}{}",
                     info.command,
-                    info.time.as_secs_f64()
+                    info.time.as_secs_f64(),
+                    deviation_note(info)
                 )
             }
             Sendable::Raw(info) => format!("Raw message: {}", info),
-            _ => unimplemented!(),
+            Sendable::Alert(alert) => {
+                format!("Alert \"{}\" from \"{}\"", alert.watcher, alert.command)
+            }
+            Sendable::Table(table) => format!("Table: {}", table.name),
+            Sendable::Diff(diff) => format!("Diff of runs {} and {}", diff.a, diff.b),
+            Sendable::Image((_, name, _)) | Sendable::File((_, name, _)) => {
+                format!("File: {}", name)
+            }
         };
         let body = match info {
             Sendable::CommandInfo(info) => {
-                format!("STDOUT:\n{}\n\nSTDERR:\n{}", info.stdout, info.stderr)
+                format!(
+                    "{}\n\nSTDOUT{}:\n{}\n\nSTDERR{}:\n{}",
+                    status_line(info),
+                    invalid_note(info.stdout_invalid),
+                    excerpt(
+                        &strip(&info.stdout),
+                        info.stdout_dropped,
+                        &self.config.limits
+                    ),
+                    invalid_note(info.stderr_invalid),
+                    excerpt(
+                        &strip(&info.stderr),
+                        info.stderr_dropped,
+                        &self.config.limits
+                    )
+                )
             }
             Sendable::Raw(info) => info.to_string(),
-            _ => unimplemented!(),
+            Sendable::Alert(alert) => format!("{}\n\n{}", alert, alert.context),
+            Sendable::Table(table) => table.to_text(),
+            Sendable::Diff(diff) => diff.to_string(),
+            Sendable::Image((_, name, _)) | Sendable::File((_, name, _)) => {
+                format!("Attached {}", name)
+            }
+        };
+        let html = match info {
+            Sendable::CommandInfo(info) => Some(format!(
+                "<p>{}</p><p><b>STDOUT{}:</b></p><pre>{}</pre><p><b>STDERR{}:</b></p><pre>{}</pre>",
+                status_line(info),
+                invalid_note(info.stdout_invalid),
+                to_html(&excerpt(
+                    &info.stdout,
+                    info.stdout_dropped,
+                    &self.config.limits
+                )),
+                invalid_note(info.stderr_invalid),
+                to_html(&excerpt(
+                    &info.stderr,
+                    info.stderr_dropped,
+                    &self.config.limits
+                ))
+            )),
+            Sendable::Table(table) => Some(table.to_html()),
+            Sendable::Diff(diff) => Some(diff.to_html()),
+            _ => None,
+        };
+        let attachment = match info {
+            Sendable::Image((mime, name, data)) | Sendable::File((mime, name, data)) => {
+                Some(attachment(mime, name, data)?)
+            }
+            _ => None,
         };
         let email = Message::builder()
             .from(match self.config.username.parse() {
@@ -259,8 +376,16 @@ impl Backend for SmtpEmailBackend {
                     )))
                 }
             })
-            .subject(subject)
-            .body(body);
+            .subject(subject);
+        let email = match (attachment, html) {
+            (Some(attachment), _) => email.multipart(
+                MultiPart::mixed()
+                    .singlepart(SinglePart::plain(body))
+                    .singlepart(attachment),
+            ),
+            (None, Some(html)) => email.multipart(MultiPart::alternative_plain_html(body, html)),
+            (None, None) => email.body(body),
+        };
         let email = match email {
             Ok(email) => email,
             Err(_) => return Err(BackendError::Unknown("Failed to generate email".into())),
@@ -271,6 +396,27 @@ impl Backend for SmtpEmailBackend {
             Err(_) => Err(BackendError::ServerError("Failed to send email".into())),
         }
     }
+
+    fn limits(&self) -> &OutputLimits {
+        &self.config.limits
+    }
+}
+
+fn attachment(mime: &Mime, name: &str, data: &[u8]) -> Result<SinglePart, BackendError> {
+    let content_type = match ContentType::parse(mime.as_ref()) {
+        Ok(content_type) => content_type,
+        Err(_) => {
+            return Err(BackendError::Unknown(format!(
+                "Failed to use {} as a content type",
+                mime
+            )))
+        }
+    };
+    let file_name = match Path::new(name).file_name() {
+        Some(file_name) => file_name.to_string_lossy().into_owned(),
+        None => name.to_owned(),
+    };
+    Ok(Attachment::new(file_name).body(data.to_vec(), content_type))
 }
 
 async fn delete_message(
@@ -281,7 +427,7 @@ async fn delete_message(
         .store(format!("{}", seq), "+FLAGS (\\Deleted)")
         .await?;
     let _updates: Vec<_> = updates_stream.try_collect().await?;
-    session.expunge().await?;
+    let _expunged: Vec<_> = session.expunge().await?.try_collect().await?;
     info!("Deleted message {}", seq);
     Ok(())
 }
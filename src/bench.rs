@@ -0,0 +1,133 @@
+use crate::backends::backend::Sendable;
+use crate::chart::histogram;
+use crate::runner::CommandInfo;
+use crate::table::Table;
+
+/// Summary of a measure over the runs
+pub struct Stats {
+    pub mean: f64,
+    pub median: f64,
+    /// Sample standard deviation
+    pub stddev: f64,
+    pub min: f64,
+    pub max: f64,
+}
+
+impl Stats {
+    pub fn of(values: &[f64]) -> Option<Self> {
+        if values.is_empty() {
+            return None;
+        }
+        let mut sorted = values.to_vec();
+        sorted.sort_by(f64::total_cmp);
+        let n = sorted.len();
+        let mean = sorted.iter().sum::<f64>() / n as f64;
+        let median = if n.is_multiple_of(2) {
+            (sorted[n / 2 - 1] + sorted[n / 2]) / 2.0
+        } else {
+            sorted[n / 2]
+        };
+        let variance = match n {
+            1 => 0.0,
+            _ => sorted.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (n - 1) as f64,
+        };
+        Some(Self {
+            mean,
+            median,
+            stddev: variance.sqrt(),
+            min: sorted[0],
+            max: sorted[n - 1],
+        })
+    }
+}
+
+/// Runs of the same command repeated to measure it
+pub struct Bench {
+    pub command: String,
+    pub runs: Vec<CommandInfo>,
+}
+
+impl Bench {
+    fn failed(&self) -> usize {
+        self.runs
+            .iter()
+            .filter(|info| !info.status.is_some_and(|status| status.success()))
+            .count()
+    }
+
+    fn durations(&self) -> Vec<f64> {
+        self.runs
+            .iter()
+            .map(|info| info.time.as_secs_f64())
+            .collect()
+    }
+
+    /// Statistics of the duration and resource use as a table
+    pub fn table(&self) -> Table {
+        let usage = |measure: fn(&CommandInfo) -> Option<f64>| -> Vec<f64> {
+            self.runs.iter().filter_map(measure).collect()
+        };
+        let measures = [
+            ("time (s)", self.durations()),
+            (
+                "user CPU (s)",
+                usage(|info| Some(info.usage?.user.as_secs_f64())),
+            ),
+            (
+                "system CPU (s)",
+                usage(|info| Some(info.usage?.system.as_secs_f64())),
+            ),
+            (
+                "peak RSS (MiB)",
+                usage(|info| Some(info.usage?.max_rss as f64 / 1024.0)),
+            ),
+        ];
+        let rows = measures
+            .iter()
+            .filter_map(|(name, values)| {
+                let stats = Stats::of(values)?;
+                let mut row = vec![name.to_string()];
+                for value in [stats.mean, stats.median, stats.stddev, stats.min, stats.max] {
+                    row.push(format!("{:.3}", value));
+                }
+                Some(row)
+            })
+            .collect();
+
+        let mut name = format!(
+            "Benchmark of \"{}\": {} runs",
+            self.command,
+            self.runs.len()
+        );
+        if self.failed() > 0 {
+            name += &format!(", {} failed", self.failed());
+        }
+        Table {
+            name,
+            header: ["", "mean", "median", "stddev", "min", "max"]
+                .map(str::to_owned)
+                .to_vec(),
+            head: rows,
+            omitted: 0,
+            tail: Vec::new(),
+        }
+    }
+
+    /// The statistics and a histogram of the durations
+    pub fn report(&self) -> Vec<Sendable> {
+        let mut report = vec![Sendable::Table(self.table())];
+        match histogram(
+            &format!("Duration of \"{}\"", self.command),
+            "seconds",
+            &self.durations(),
+        ) {
+            Ok(png) => report.push(Sendable::Image((
+                mime::IMAGE_PNG,
+                "durations.png".to_owned(),
+                png,
+            ))),
+            Err(err) => report.push(Sendable::Raw(err.to_string())),
+        }
+        report
+    }
+}
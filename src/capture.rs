@@ -0,0 +1,213 @@
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io,
+    io::{BufWriter, Write},
+    path::PathBuf,
+};
+
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8};
+
+/// Keeps the last `max_bytes`/`max_lines` written to it.
+pub struct RingBuffer {
+    data: VecDeque<u8>,
+    max_bytes: usize,
+    max_lines: usize,
+    lines: usize,
+    dropped_lines: usize,
+    /// Total bytes dropped from the front, to find UTF-16 code units
+    dropped_bytes: usize,
+    /// Whether the kept bytes start part way through a line
+    partial: bool,
+}
+
+impl RingBuffer {
+    pub fn new(max_bytes: usize, max_lines: usize) -> Self {
+        Self {
+            data: VecDeque::new(),
+            max_bytes,
+            max_lines,
+            lines: 0,
+            dropped_lines: 0,
+            dropped_bytes: 0,
+            partial: false,
+        }
+    }
+
+    fn drop_front(&mut self, n: usize) {
+        if n == 0 {
+            return;
+        }
+        self.partial = self.data[n - 1] != b'\n';
+        let newlines = self.data.drain(..n).filter(|b| *b == b'\n').count();
+        self.lines -= newlines;
+        self.dropped_lines += newlines;
+        self.dropped_bytes += n;
+    }
+
+    /// The kept bytes and the number of lines dropped, counting a partly
+    /// dropped line as a whole one. The bytes start on a character boundary
+    /// of `charset`
+    pub fn into_inner(mut self, charset: &'static Encoding) -> (Vec<u8>, usize) {
+        if self.partial {
+            if charset == UTF_8 {
+                // Continuation bytes are marked, skip to the next character
+                while self
+                    .data
+                    .front()
+                    .is_some_and(|b| b & 0b1100_0000 == 0b1000_0000)
+                {
+                    self.data.pop_front();
+                }
+            } else if charset == UTF_16LE || charset == UTF_16BE {
+                if self.dropped_bytes % 2 == 1 {
+                    self.data.pop_front();
+                }
+                // Skip the second half of a surrogate pair
+                let high = match charset == UTF_16LE {
+                    true => self.data.get(1),
+                    false => self.data.front(),
+                };
+                if high.is_some_and(|b| (0xDC..=0xDF).contains(b)) {
+                    self.data.drain(..2);
+                }
+            } else if let Some(end) = self.data.iter().position(|b| *b == b'\n') {
+                // Other multi-byte charsets can't be resynchronized, but they
+                // never use a newline byte within a character
+                self.data.drain(..end + 1);
+            }
+            self.dropped_lines += 1;
+        }
+        (self.data.into(), self.dropped_lines)
+    }
+}
+
+impl Write for RingBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Only the end of a huge write can survive, so don't copy the rest
+        let kept = &buf[buf.len().saturating_sub(self.max_bytes)..];
+        let skipped = &buf[..buf.len() - kept.len()];
+        if !skipped.is_empty() {
+            let len = self.data.len();
+            self.drop_front(len);
+            self.dropped_lines += skipped.iter().filter(|b| **b == b'\n').count();
+            self.dropped_bytes += skipped.len();
+            self.partial = skipped.last() != Some(&b'\n');
+        }
+
+        self.lines += kept.iter().filter(|b| **b == b'\n').count();
+        self.data.extend(kept);
+
+        if self.data.len() > self.max_bytes {
+            self.drop_front(self.data.len() - self.max_bytes);
+        }
+        while self.lines > self.max_lines {
+            let end = self
+                .data
+                .iter()
+                .position(|b| *b == b'\n')
+                .expect("Impossible error, counted a missing line");
+            self.drop_front(end + 1);
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Keeps the tail of a stream in memory, writing all of it to `log` if set.
+pub struct Capture {
+    pub tail: RingBuffer,
+    pub log: Option<(PathBuf, BufWriter<File>)>,
+}
+
+impl Capture {
+    pub fn new(max_bytes: usize, max_lines: usize, log: Option<PathBuf>) -> io::Result<Self> {
+        let log = match log {
+            Some(path) => {
+                let file = File::create(&path)?;
+                Some((path, BufWriter::new(file)))
+            }
+            None => None,
+        };
+        Ok(Self {
+            tail: RingBuffer::new(max_bytes, max_lines),
+            log,
+        })
+    }
+}
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.tail.write_all(buf)?;
+        if let Some((_, log)) = &mut self.log {
+            log.write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some((_, log)) = &mut self.log {
+            log.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kept(buffer: RingBuffer) -> (String, usize) {
+        let (data, dropped) = buffer.into_inner(UTF_8);
+        (String::from_utf8(data).unwrap(), dropped)
+    }
+
+    #[test]
+    fn keeps_everything_under_the_limits() {
+        let mut buffer = RingBuffer::new(100, 10);
+        buffer.write_all(b"one\ntwo\n").unwrap();
+        assert_eq!(kept(buffer), ("one\ntwo\n".to_owned(), 0));
+    }
+
+    #[test]
+    fn drops_lines_over_the_limit() {
+        let mut buffer = RingBuffer::new(100, 2);
+        for line in ["a\n", "b\n", "c\n", "d\n"] {
+            buffer.write_all(line.as_bytes()).unwrap();
+        }
+        assert_eq!(kept(buffer), ("c\nd\n".to_owned(), 2));
+    }
+
+    #[test]
+    fn drops_bytes_over_the_limit() {
+        let mut buffer = RingBuffer::new(6, 100);
+        buffer.write_all(b"hello\nworld\n").unwrap();
+        assert_eq!(kept(buffer), ("world\n".to_owned(), 1));
+    }
+
+    #[test]
+    fn counts_a_partly_dropped_line() {
+        let mut buffer = RingBuffer::new(4, 100);
+        buffer.write_all(b"abcdef\n").unwrap();
+        assert_eq!(kept(buffer), ("def\n".to_owned(), 1));
+    }
+
+    #[test]
+    fn trims_to_a_utf8_boundary() {
+        let mut buffer = RingBuffer::new(5, 100);
+        buffer.write_all("éé".as_bytes()).unwrap();
+        buffer.write_all("éé".as_bytes()).unwrap();
+        assert_eq!(kept(buffer), ("éé".to_owned(), 1));
+    }
+
+    #[test]
+    fn trims_to_a_utf16_boundary() {
+        let mut buffer = RingBuffer::new(3, 100);
+        buffer.write_all(&[b'a', 0, b'b', 0]).unwrap();
+        assert_eq!(buffer.into_inner(UTF_16LE), (vec![b'b', 0], 1));
+    }
+}
@@ -0,0 +1,225 @@
+use std::{error::Error, fs, io::Cursor};
+
+use image::{ImageOutputFormat, RgbImage};
+use lazy_static::lazy_static;
+use log::*;
+use plotters::{prelude::*, style::register_font};
+use thiserror::Error;
+
+const WIDTH: u32 = 1000;
+const PANEL_HEIGHT: u32 = 300;
+
+const FONTS: [&str; 4] = [
+    "/usr/share/fonts/truetype/dejavu/DejaVuSans.ttf",
+    "/usr/share/fonts/TTF/DejaVuSans.ttf",
+    "/usr/share/fonts/dejavu/DejaVuSans.ttf",
+    "/usr/share/fonts/truetype/liberation/LiberationSans-Regular.ttf",
+];
+
+lazy_static! {
+    /// Whether a font was found, charts are drawn without text otherwise
+    static ref HAS_FONT: bool = {
+        let font = FONTS.iter().find_map(|path| fs::read(path).ok());
+        match font {
+            Some(font) => {
+                let font: &'static [u8] = Box::leak(font.into_boxed_slice());
+                register_font("sans-serif", FontStyle::Normal, font).is_ok()
+            }
+            None => {
+                warn!("No font found, charts won't have labels");
+                false
+            }
+        }
+    };
+}
+
+const COLORS: [RGBColor; 6] = [
+    RGBColor(31, 119, 180),
+    RGBColor(255, 127, 14),
+    RGBColor(44, 160, 44),
+    RGBColor(214, 39, 40),
+    RGBColor(148, 103, 189),
+    RGBColor(140, 86, 75),
+];
+
+#[derive(Error, Debug)]
+#[error("Failed to draw chart: {0}")]
+pub struct ChartError(String);
+
+pub struct Series {
+    pub name: String,
+    pub points: Vec<(f64, f64)>,
+}
+
+/// One chart in a stack of charts sharing the x axis
+pub struct Panel {
+    pub title: String,
+    pub series: Vec<Series>,
+}
+
+/// The range the finite values span
+fn bounds(values: impl Iterator<Item = f64>) -> (f64, f64) {
+    let (min, max) = values
+        .filter(|v| v.is_finite())
+        .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), v| {
+            (min.min(v), max.max(v))
+        });
+    if min > max {
+        (0.0, 1.0)
+    } else if min == max {
+        (min - 0.5, max + 0.5)
+    } else {
+        (min, max)
+    }
+}
+
+fn encode(buf: Vec<u8>, height: u32) -> Result<Vec<u8>, ChartError> {
+    let image = match RgbImage::from_raw(WIDTH, height, buf) {
+        Some(image) => image,
+        None => return Err(ChartError("bitmap has the wrong size".to_owned())),
+    };
+    let mut png = Cursor::new(Vec::new());
+    match image.write_to(&mut png, ImageOutputFormat::Png) {
+        Ok(_) => Ok(png.into_inner()),
+        Err(err) => Err(ChartError(err.to_string())),
+    }
+}
+
+/// Draws the panels one above the other as a PNG
+pub fn line_chart(x_label: &str, panels: &[Panel]) -> Result<Vec<u8>, ChartError> {
+    let height = PANEL_HEIGHT * panels.len().max(1) as u32;
+    let mut buf = vec![0; (WIDTH * height * 3) as usize];
+    match draw_lines(&mut buf, height, x_label, panels) {
+        Ok(_) => encode(buf, height),
+        Err(err) => Err(ChartError(err.to_string())),
+    }
+}
+
+fn draw_lines(
+    buf: &mut [u8],
+    height: u32,
+    x_label: &str,
+    panels: &[Panel],
+) -> Result<(), Box<dyn Error>> {
+    let root = BitMapBackend::with_buffer(buf, (WIDTH, height)).into_drawing_area();
+    root.fill(&WHITE)?;
+    let areas = root.split_evenly((panels.len().max(1), 1));
+
+    let (x_min, x_max) = bounds(
+        panels
+            .iter()
+            .flat_map(|panel| panel.series.iter())
+            .flat_map(|series| series.points.iter().map(|(x, _)| *x)),
+    );
+
+    for (panel, area) in panels.iter().zip(areas.iter()) {
+        let (y_min, y_max) = bounds(
+            panel
+                .series
+                .iter()
+                .flat_map(|series| series.points.iter().map(|(_, y)| *y)),
+        );
+
+        let mut builder = ChartBuilder::on(area);
+        builder.margin(10);
+        if *HAS_FONT {
+            builder
+                .caption(&panel.title, ("sans-serif", 18))
+                .x_label_area_size(30)
+                .y_label_area_size(60);
+        }
+        let mut chart = builder.build_cartesian_2d(x_min..x_max, y_min..y_max)?;
+        if *HAS_FONT {
+            chart.configure_mesh().x_desc(x_label).draw()?;
+        }
+
+        for (i, series) in panel.series.iter().enumerate() {
+            let color = COLORS[i % COLORS.len()];
+            // A line of a few points is hard to see, a single point can't be seen at all
+            if series.points.len() < 20 {
+                chart.draw_series(
+                    series
+                        .points
+                        .iter()
+                        .map(|point| Circle::new(*point, 3, color.filled())),
+                )?;
+            }
+            let drawn =
+                chart.draw_series(LineSeries::new(series.points.iter().copied(), &color))?;
+            if *HAS_FONT {
+                drawn
+                    .label(&series.name)
+                    .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], color));
+            }
+        }
+        if *HAS_FONT && panel.series.len() > 1 {
+            chart
+                .configure_series_labels()
+                .background_style(WHITE.mix(0.8))
+                .border_style(BLACK)
+                .draw()?;
+        }
+    }
+
+    root.present()?;
+    Ok(())
+}
+
+/// Draws how the values are spread as a PNG histogram
+pub fn histogram(title: &str, x_label: &str, values: &[f64]) -> Result<Vec<u8>, ChartError> {
+    let height = PANEL_HEIGHT * 2;
+    let mut buf = vec![0; (WIDTH * height * 3) as usize];
+    match draw_histogram(&mut buf, height, title, x_label, values) {
+        Ok(_) => encode(buf, height),
+        Err(err) => Err(ChartError(err.to_string())),
+    }
+}
+
+fn draw_histogram(
+    buf: &mut [u8],
+    height: u32,
+    title: &str,
+    x_label: &str,
+    values: &[f64],
+) -> Result<(), Box<dyn Error>> {
+    let root = BitMapBackend::with_buffer(buf, (WIDTH, height)).into_drawing_area();
+    root.fill(&WHITE)?;
+
+    let (min, max) = bounds(values.iter().copied());
+    let bins = ((values.len() as f64).sqrt().ceil() as usize).clamp(5, 30);
+    let width = (max - min) / bins as f64;
+    let mut counts = vec![0u32; bins];
+    for value in values.iter().filter(|v| v.is_finite()) {
+        let bin = ((value - min) / width) as usize;
+        counts[bin.min(bins - 1)] += 1;
+    }
+
+    let mut builder = ChartBuilder::on(&root);
+    builder.margin(10);
+    if *HAS_FONT {
+        builder
+            .caption(title, ("sans-serif", 18))
+            .x_label_area_size(30)
+            .y_label_area_size(60);
+    }
+    let top = counts.iter().copied().max().unwrap_or(0) + 1;
+    let mut chart = builder.build_cartesian_2d(min..max, 0u32..top)?;
+    if *HAS_FONT {
+        chart
+            .configure_mesh()
+            .x_desc(x_label)
+            .y_desc("runs")
+            .draw()?;
+    }
+    chart.draw_series(counts.iter().enumerate().map(|(i, count)| {
+        // A gap between the bars so neighbouring bins can be told apart
+        let left = min + width * (i as f64 + 0.05);
+        Rectangle::new(
+            [(left, 0), (left + width * 0.9, *count)],
+            COLORS[0].filled(),
+        )
+    }))?;
+
+    root.present()?;
+    Ok(())
+}
@@ -1,12 +1,46 @@
-use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Duration,
+};
 
-#[derive(Deserialize)]
+use serde::{de, Deserialize, Deserializer};
+
+#[derive(Deserialize, Clone)]
 pub struct Config {
     pub email: Option<EmailConfig>,
     pub matrix: Option<MatrixConfig>,
+    pub upload: Option<UploadConfig>,
+    /// MIME types to use for file extensions, overriding detection
+    #[serde(default)]
+    pub mime: HashMap<String, String>,
+    #[serde(default)]
+    pub capture: CaptureConfig,
+    pub sampling: Option<SamplingConfig>,
+    #[serde(default)]
+    pub job: JobConfig,
+    #[serde(default)]
+    pub schedule: ScheduleConfig,
+    #[serde(default)]
+    pub notify: NotifyConfig,
+    /// Patterns to look for in the output while the command runs
+    #[serde(default)]
+    pub watchers: Vec<WatcherConfig>,
+    #[serde(default)]
+    pub progress: ProgressConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    #[serde(default)]
+    pub tables: TableConfig,
+    #[serde(default)]
+    pub history: HistoryConfig,
+    #[serde(default)]
+    pub diff: DiffConfig,
+    /// Flag runs much slower or bigger than earlier ones, needs the history
+    pub regression: Option<RegressionConfig>,
+    pub sweep: Option<SweepConfig>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 pub struct EmailConfig {
     pub address: String,
     pub username: String,
@@ -14,12 +48,371 @@ pub struct EmailConfig {
     pub smtp_server: String,
     pub imap_server: String,
     pub imap_port: u16,
+    #[serde(default)]
+    pub limits: OutputLimits,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 pub struct MatrixConfig {
     pub address: String,
     pub username: String,
     pub password: String,
     pub room: String,
+    #[serde(default)]
+    pub limits: OutputLimits,
+}
+
+/// How much command output a backend puts in a single message
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct OutputLimits {
+    /// Lines kept from the start of stdout/stderr
+    pub head_lines: usize,
+    /// Lines kept from the end of stdout/stderr
+    pub tail_lines: usize,
+    /// Largest excerpt of a single stream, in bytes
+    pub max_body_size: usize,
+    /// Largest attachment the backend accepts, in bytes
+    pub max_attachment_size: usize,
+}
+
+impl Default for OutputLimits {
+    fn default() -> Self {
+        Self {
+            head_lines: 20,
+            tail_lines: 80,
+            max_body_size: 16 * 1024,
+            max_attachment_size: 20 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct UploadConfig {
+    /// Directory incoming attachments are saved into
+    pub directory: String,
+    /// Largest attachment accepted, in bytes
+    #[serde(default = "default_max_upload_size")]
+    pub max_size: usize,
+    /// Rerun the command after an upload is saved
+    #[serde(default)]
+    pub rerun: bool,
+}
+
+fn default_max_upload_size() -> usize {
+    10 * 1024 * 1024
+}
+
+/// How much of the command's output is kept
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct CaptureConfig {
+    /// Bytes of each stream kept in memory
+    pub max_bytes: usize,
+    /// Lines of each stream kept in memory
+    pub max_lines: usize,
+    /// Directory the complete streams are written to, if any
+    pub log_dir: Option<String>,
+    /// Character set the command writes its output in (default utf-8)
+    pub charset: Option<String>,
+    /// Run the command under a pseudo-terminal
+    pub pty: bool,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        Self {
+            max_bytes: 4 * 1024 * 1024,
+            max_lines: 100_000,
+            log_dir: None,
+            charset: None,
+            pty: false,
+        }
+    }
+}
+
+/// Periodic sampling of the command's CPU, memory and disk use
+#[derive(Deserialize, Clone)]
+pub struct SamplingConfig {
+    /// Seconds between samples
+    #[serde(
+        default = "default_sampling_interval",
+        deserialize_with = "positive_seconds"
+    )]
+    pub interval: f64,
+}
+
+fn default_sampling_interval() -> f64 {
+    5.0
+}
+
+/// Seconds that can be used as an interval, rejecting zero, negative and
+/// overly large values when the config is read
+fn positive_seconds<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    let seconds = f64::deserialize(deserializer)?;
+    match Duration::try_from_secs_f64(seconds) {
+        Ok(duration) if !duration.is_zero() => Ok(seconds),
+        _ => Err(de::Error::custom(format!(
+            "expected a positive number of seconds, got {}",
+            seconds
+        ))),
+    }
+}
+
+fn seconds<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    let seconds = f64::deserialize(deserializer)?;
+    match Duration::try_from_secs_f64(seconds) {
+        Ok(_) => Ok(seconds),
+        Err(_) => Err(de::Error::custom(format!(
+            "expected a number of seconds, got {}",
+            seconds
+        ))),
+    }
+}
+
+/// How the command is started
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct JobConfig {
+    /// Shell and flags to run the command with, e.g. "zsh -lc" (default $SHELL)
+    pub shell: Option<String>,
+    /// Working directory of the command
+    pub cwd: Option<String>,
+    /// Extra environment variables for the command
+    pub env: HashMap<String, String>,
+    /// Times to rerun a failed command before reporting the failure
+    pub retries: u32,
+    /// Seconds to wait before the first retry, doubled for each one after
+    pub retry_backoff: f64,
+    /// Exit codes worth retrying, any failure is retried when empty
+    pub retry_on_exit_codes: Vec<i32>,
+}
+
+impl Default for JobConfig {
+    fn default() -> Self {
+        Self {
+            shell: None,
+            cwd: None,
+            env: HashMap::new(),
+            retries: 0,
+            retry_backoff: 30.0,
+            retry_on_exit_codes: Vec::new(),
+        }
+    }
+}
+
+/// Reruns at set times
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct ScheduleConfig {
+    /// Cron expression to rerun the command on, e.g. "0 3 * * *"
+    pub cron: Option<String>,
+    /// Where runs scheduled by command are kept between restarts
+    pub file: String,
+}
+
+impl Default for ScheduleConfig {
+    fn default() -> Self {
+        Self {
+            cron: None,
+            file: "./schedule.json".to_owned(),
+        }
+    }
+}
+
+/// Which runs are reported
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct NotifyConfig {
+    /// Rules like "failure", "duration>30m" or "stdout~/ERROR/", a run is
+    /// reported when any matches (default every run is)
+    pub notify_on: Vec<String>,
+    /// Time of day to send the digest of runs that weren't reported
+    pub digest_at: String,
+    /// Where unreported runs are kept until the digest
+    pub digest_file: String,
+}
+
+impl Default for NotifyConfig {
+    fn default() -> Self {
+        Self {
+            notify_on: Vec::new(),
+            digest_at: "09:00".to_owned(),
+            digest_file: "./digest.json".to_owned(),
+        }
+    }
+}
+
+/// Where finished runs are kept
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct HistoryConfig {
+    /// Keep a history of runs, off by default as it grows with every run
+    pub enabled: bool,
+    /// Directory of the history, with copies of output that wasn't logged
+    pub dir: String,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            dir: "./history".to_owned(),
+        }
+    }
+}
+
+/// How the output of two runs is compared
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct DiffConfig {
+    /// Regexes for parts of the output that change every run, like
+    /// timestamps, masked before comparing
+    pub ignore: Vec<String>,
+    /// Unchanged lines shown around each change
+    pub context: usize,
+}
+
+impl Default for DiffConfig {
+    fn default() -> Self {
+        Self {
+            ignore: vec![r"\d{4}-\d{2}-\d{2}[T ]\d{2}:\d{2}:\d{2}(\.\d+)?".to_owned()],
+            context: 3,
+        }
+    }
+}
+
+/// When a run is flagged as deviating from earlier runs of the same command
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct RegressionConfig {
+    /// Earlier successful runs the median is taken over
+    pub window: usize,
+    /// Fewest earlier runs needed to compare with
+    pub min_runs: usize,
+    /// Relative change in duration that's flagged, 0.2 being 20%
+    pub duration_threshold: f64,
+    /// Relative change in peak memory that's flagged
+    pub memory_threshold: f64,
+}
+
+impl Default for RegressionConfig {
+    fn default() -> Self {
+        Self {
+            window: 10,
+            min_runs: 3,
+            duration_threshold: 0.2,
+            memory_threshold: 0.2,
+        }
+    }
+}
+
+/// Runs of the command over every combination of parameter values
+#[derive(Deserialize, Clone)]
+pub struct SweepConfig {
+    /// Values of each parameter, given to the command as environment
+    /// variables and put in place of `{name}` in the command
+    pub parameters: BTreeMap<String, Vec<toml::Value>>,
+    /// Regex for the metric in stdout, from a group named `value` or else
+    /// the first group, the last match counts
+    pub metric: Option<String>,
+    /// Sort the results with the smallest metric first
+    #[serde(default)]
+    pub minimize: bool,
+    /// Runs at the same time
+    #[serde(default = "default_sweep_parallel")]
+    pub parallel: usize,
+}
+
+fn default_sweep_parallel() -> usize {
+    1
+}
+
+/// A pattern that sends an alert as soon as a line of output matches it
+#[derive(Deserialize, Clone)]
+pub struct WatcherConfig {
+    /// Regex the line is matched against
+    pub pattern: String,
+    /// Name to use in the alert (default the pattern)
+    pub name: Option<String>,
+    /// Lines to send from before and after the match
+    #[serde(default = "default_watcher_context")]
+    pub context: usize,
+    /// Least number of seconds between two alerts of this watcher
+    #[serde(default = "default_watcher_interval", deserialize_with = "seconds")]
+    pub min_interval: f64,
+    #[serde(default)]
+    pub action: WatcherAction,
+}
+
+fn default_watcher_context() -> usize {
+    5
+}
+
+fn default_watcher_interval() -> f64 {
+    600.0
+}
+
+/// What to do besides alerting when a watcher matches
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum WatcherAction {
+    #[default]
+    NotifyOnly,
+    /// Stop the command with SIGTERM
+    Kill,
+}
+
+/// How to read the command's progress from its output
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct ProgressConfig {
+    /// Regexes capturing the done and total counts, as groups named `done`
+    /// and `total` or else the first two groups
+    pub patterns: Vec<String>,
+    /// Read progress from tqdm bars
+    pub tqdm: bool,
+}
+
+impl Default for ProgressConfig {
+    fn default() -> Self {
+        Self {
+            patterns: Vec::new(),
+            tqdm: true,
+        }
+    }
+}
+
+/// Values to read from the output and chart at the end of the run
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct MetricsConfig {
+    /// Regexes where each named group is a metric, a group named `step` is
+    /// used for the x axis instead of the time
+    pub patterns: Vec<String>,
+}
+
+/// How CSV artifacts are shown in messages
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct TableConfig {
+    /// Show CSV artifacts as tables, they're attached either way
+    pub enabled: bool,
+    /// Largest CSV file in bytes shown as a table
+    pub max_size: usize,
+    /// Rows shown from the start of the table
+    pub head_rows: usize,
+    /// Rows shown from the end of the table
+    pub tail_rows: usize,
+}
+
+impl Default for TableConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_size: 64 * 1024,
+            head_rows: 10,
+            tail_rows: 10,
+        }
+    }
 }
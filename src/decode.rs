@@ -0,0 +1,47 @@
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE};
+
+pub struct Decoded {
+    pub text: String,
+    /// Whether bytes that aren't valid in the charset were replaced
+    pub invalid: bool,
+    /// The raw bytes, when they look like binary data rather than text
+    pub binary: Option<Vec<u8>>,
+}
+
+/// Guesses whether `bytes` is binary data by looking for NUL bytes and
+/// control characters that don't show up in terminal output.
+pub fn is_binary(bytes: &[u8]) -> bool {
+    let sample = &bytes[..bytes.len().min(8192)];
+    if sample.contains(&0) {
+        return true;
+    }
+    let control = sample
+        .iter()
+        .filter(|b| **b < 0x20 && !b"\t\n\r\x08\x0c\x1b".contains(b))
+        .count();
+    control * 10 > sample.len()
+}
+
+/// Decodes command output without failing on invalid bytes.
+pub fn decode(bytes: Vec<u8>, charset: &'static Encoding) -> Decoded {
+    let binary = |bytes: Vec<u8>| Decoded {
+        text: format!("<{} bytes of binary output>", bytes.len()),
+        invalid: false,
+        binary: Some(bytes),
+    };
+    // Most UTF-16 characters have a NUL byte, so look at the text instead
+    let utf16 = charset == UTF_16LE || charset == UTF_16BE;
+    if !utf16 && is_binary(&bytes) {
+        return binary(bytes);
+    }
+
+    let (text, invalid) = charset.decode_without_bom_handling(&bytes);
+    if utf16 && is_binary(text.as_bytes()) {
+        return binary(bytes);
+    }
+    Decoded {
+        text: text.into_owned(),
+        invalid,
+        binary: None,
+    }
+}
@@ -0,0 +1,227 @@
+use std::{fmt::Display, fs, path::PathBuf};
+
+use regex::Regex;
+use similar::{ChangeTag, TextDiff};
+use thiserror::Error;
+
+use crate::ansi::{escape_html, strip};
+use crate::backends::backend::Sendable;
+use crate::config::{DiffConfig, OutputLimits};
+use crate::history::{History, Record};
+use crate::output::exceeds;
+
+#[derive(Error, Debug)]
+pub enum DiffError {
+    #[error("Need two runs in the history to diff, there are {0}")]
+    NotEnoughRuns(usize),
+    #[error("No run {0} in the history")]
+    NoSuchRun(u32),
+    #[error("No run of \"{1}\" before run {0} to diff with")]
+    NoEarlierRun(u32, String),
+    #[error("No {1} kept for run {0}")]
+    NoLog(u32, String),
+    #[error("Failed to read log {0}:\n {1}")]
+    ReadError(String, String),
+    #[error("Invalid ignore pattern {0}:\n {1}")]
+    PatternError(String, String),
+    #[error("{0}")]
+    HistoryError(String),
+}
+
+/// Changes to one stream between two runs
+pub struct StreamDiff {
+    pub stream: &'static str,
+    /// Unified diff, empty when nothing changed
+    pub diff: String,
+    pub added: usize,
+    pub removed: usize,
+}
+
+/// Changes to the output between two runs from the history
+pub struct RunDiff {
+    pub a: u32,
+    pub b: u32,
+    pub streams: Vec<StreamDiff>,
+}
+
+impl RunDiff {
+    /// Which streams changed and by how many lines
+    pub fn summary(&self) -> String {
+        let changes: Vec<String> = self
+            .streams
+            .iter()
+            .map(|stream| match stream.diff.is_empty() {
+                true => format!("{} unchanged", stream.stream),
+                false => format!("{} +{} -{}", stream.stream, stream.added, stream.removed),
+            })
+            .collect();
+        format!("Runs {} and {}: {}", self.a, self.b, changes.join(", "))
+    }
+
+    pub fn to_html(&self) -> String {
+        let mut html = format!("<p><b>{}</b></p>", escape_html(&self.summary()));
+        for stream in self.streams.iter().filter(|s| !s.diff.is_empty()) {
+            html.push_str(&format!(
+                "<p><b>{}:</b></p><pre>",
+                stream.stream.to_uppercase()
+            ));
+            for line in stream.diff.lines() {
+                let color = match line.chars().next() {
+                    _ if line.starts_with("+++") || line.starts_with("---") => None,
+                    Some('+') => Some("#22863a"),
+                    Some('-') => Some("#cb2431"),
+                    Some('@') => Some("#6f42c1"),
+                    _ => None,
+                };
+                match color {
+                    Some(color) => html.push_str(&format!(
+                        "<font color=\"{}\">{}</font>\n",
+                        color,
+                        escape_html(line)
+                    )),
+                    None => html.push_str(&format!("{}\n", escape_html(line))),
+                }
+            }
+            html.push_str("</pre>");
+        }
+        html
+    }
+
+    /// The diff as a message, or attached as a file when it's too long for one
+    pub fn into_sendables(self, limits: &OutputLimits) -> Vec<Sendable> {
+        let text = self.to_string();
+        if !exceeds(&text, limits) {
+            return vec![Sendable::Diff(self)];
+        }
+        let name = format!("runs-{}-{}.diff", self.a, self.b);
+        let mime = "text/x-diff".parse().unwrap_or(mime::TEXT_PLAIN);
+        vec![
+            Sendable::Raw(format!("{}, the diff is attached", self.summary())),
+            Sendable::File((mime, name, text.into_bytes())),
+        ]
+    }
+}
+
+impl Display for RunDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.summary())?;
+        for stream in self.streams.iter().filter(|s| !s.diff.is_empty()) {
+            write!(f, "\n\n{}:\n{}", stream.stream.to_uppercase(), stream.diff)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads a log, without escape sequences and with the ignored patterns
+/// masked so they don't show up as changes
+fn read_log(
+    record: &Record,
+    stream: &str,
+    log: &Option<PathBuf>,
+    ignore: &[Regex],
+) -> Result<String, DiffError> {
+    let Some(log) = log else {
+        return Err(DiffError::NoLog(record.id, stream.to_owned()));
+    };
+    let text = match fs::read(log) {
+        Ok(data) => strip(&String::from_utf8_lossy(&data)),
+        Err(err) => {
+            return Err(DiffError::ReadError(
+                log.display().to_string(),
+                err.to_string(),
+            ))
+        }
+    };
+    Ok(ignore.iter().fold(text, |text, regex| {
+        regex.replace_all(&text, "<ignored>").into_owned()
+    }))
+}
+
+/// Diffs the output of runs `a` and `b`, by default the last run and the
+/// one of the same command before it, or `a` and the last other run
+pub fn diff(
+    history: &History,
+    a: Option<u32>,
+    b: Option<u32>,
+    config: &DiffConfig,
+) -> Result<RunDiff, DiffError> {
+    let mut ignore = Vec::new();
+    for pattern in &config.ignore {
+        match Regex::new(pattern) {
+            Ok(regex) => ignore.push(regex),
+            Err(err) => return Err(DiffError::PatternError(pattern.clone(), err.to_string())),
+        }
+    }
+    let runs = match history.runs() {
+        Ok(runs) => runs,
+        Err(err) => return Err(DiffError::HistoryError(err.to_string())),
+    };
+    let find = |id: u32| {
+        runs.iter()
+            .find(|record| record.id == id)
+            .ok_or(DiffError::NoSuchRun(id))
+    };
+    let (a, b) = match (a, b) {
+        (Some(a), Some(b)) => (find(a)?, find(b)?),
+        (Some(a), None) => {
+            let a = find(a)?;
+            match runs.iter().rev().find(|record| record.id != a.id) {
+                Some(b) => (a, b),
+                None => return Err(DiffError::NotEnoughRuns(runs.len())),
+            }
+        }
+        (None, _) => {
+            let Some((b, earlier)) = runs.split_last() else {
+                return Err(DiffError::NotEnoughRuns(0));
+            };
+            match earlier
+                .iter()
+                .rev()
+                .find(|record| record.command == b.command)
+            {
+                Some(a) => (a, b),
+                None => return Err(DiffError::NoEarlierRun(b.id, b.command.clone())),
+            }
+        }
+    };
+
+    let mut streams = Vec::new();
+    for (stream, old, new) in [
+        ("stdout", &a.stdout_log, &b.stdout_log),
+        ("stderr", &a.stderr_log, &b.stderr_log),
+    ] {
+        let old = read_log(a, stream, old, &ignore)?;
+        let new = read_log(b, stream, new, &ignore)?;
+        let text_diff = TextDiff::from_lines(&old, &new);
+        let (mut added, mut removed) = (0, 0);
+        for change in text_diff.iter_all_changes() {
+            match change.tag() {
+                ChangeTag::Insert => added += 1,
+                ChangeTag::Delete => removed += 1,
+                ChangeTag::Equal => {}
+            }
+        }
+        let diff = match added + removed {
+            0 => String::new(),
+            _ => text_diff
+                .unified_diff()
+                .context_radius(config.context)
+                .header(
+                    &format!("run {} {}", a.id, stream),
+                    &format!("run {} {}", b.id, stream),
+                )
+                .to_string(),
+        };
+        streams.push(StreamDiff {
+            stream,
+            diff,
+            added,
+            removed,
+        });
+    }
+    Ok(RunDiff {
+        a: a.id,
+        b: b.id,
+        streams,
+    })
+}
@@ -0,0 +1,297 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use chrono::{DateTime, Local};
+use log::*;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::config::HistoryConfig;
+use crate::output::UNKNOWN_STATUS;
+use crate::runner::CommandInfo;
+use crate::usage::Usage;
+
+#[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum HistoryError {
+    #[error("Failed to create history directory {0}:\n {1}")]
+    DirError(String, String),
+    #[error("Failed to read history {0}:\n {1}")]
+    ReadError(String, String),
+    #[error("Failed to write history {0}:\n {1}")]
+    WriteError(String, String),
+}
+
+/// A finished run as kept in the history
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Record {
+    pub id: u32,
+    pub command: String,
+    /// Environment variables the command was given
+    pub parameters: BTreeMap<String, String>,
+    pub start: DateTime<Local>,
+    pub end: DateTime<Local>,
+    pub time: Duration,
+    pub status: Option<String>,
+    pub exit_code: Option<i32>,
+    pub success: bool,
+    pub usage: Option<Usage>,
+    /// Number of attempts, more than one when the command was retried
+    pub attempts: usize,
+    pub artifacts: Vec<PathBuf>,
+    pub stdout_log: Option<PathBuf>,
+    pub stderr_log: Option<PathBuf>,
+}
+
+impl Record {
+    /// One line summary for listings
+    pub fn summary(&self) -> String {
+        format!(
+            "{:>4}  {}  {:>8.1}s  {}  \"{}\"",
+            self.id,
+            self.start.format("%Y-%m-%d %H:%M"),
+            self.time.as_secs_f64(),
+            self.status.as_deref().unwrap_or(UNKNOWN_STATUS),
+            self.command
+        )
+    }
+}
+
+impl Display for Record {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Run {}: \"{}\"", self.id, self.command)?;
+        writeln!(
+            f,
+            "Started {}, ended {} ({:.1}s)",
+            self.start.format("%Y-%m-%d %H:%M:%S"),
+            self.end.format("%Y-%m-%d %H:%M:%S"),
+            self.time.as_secs_f64()
+        )?;
+        write!(f, "{}", self.status.as_deref().unwrap_or(UNKNOWN_STATUS))?;
+        if self.attempts > 1 {
+            write!(f, " after {} attempts", self.attempts)?;
+        }
+        if let Some(usage) = &self.usage {
+            write!(f, "\n{}", usage)?;
+        }
+        if !self.parameters.is_empty() {
+            let parameters: Vec<String> = self
+                .parameters
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect();
+            write!(f, "\nParameters: {}", parameters.join(" "))?;
+        }
+        if !self.artifacts.is_empty() {
+            f.write_str("\nArtifacts:")?;
+            for artifact in &self.artifacts {
+                write!(f, "\n  {}", artifact.display())?;
+            }
+        }
+        for (stream, log) in [("stdout", &self.stdout_log), ("stderr", &self.stderr_log)] {
+            if let Some(log) = log {
+                write!(f, "\n{} log: {}", stream, log.display())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The last runs, newest last
+pub struct Listing(pub Vec<Record>);
+
+impl Display for Listing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.0.is_empty() {
+            return f.write_str("No runs yet");
+        }
+        f.write_str("  id  started           duration  status  command")?;
+        for record in &self.0 {
+            write!(f, "\n{}", record.summary())?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs kept as JSON lines, with copies of the output of runs that had no
+/// log of their own
+pub struct History {
+    dir: PathBuf,
+    path: PathBuf,
+}
+
+impl History {
+    pub fn open(config: &HistoryConfig) -> Result<Self, HistoryError> {
+        let dir = PathBuf::from(&config.dir);
+        if let Err(err) = fs::create_dir_all(&dir) {
+            return Err(HistoryError::DirError(config.dir.clone(), err.to_string()));
+        }
+        Ok(Self {
+            path: dir.join("runs.jsonl"),
+            dir,
+        })
+    }
+
+    /// Every run recorded, oldest first
+    pub fn runs(&self) -> Result<Vec<Record>, HistoryError> {
+        let read_error =
+            |err: String| HistoryError::ReadError(self.path.display().to_string(), err);
+        match fs::read_to_string(&self.path) {
+            Ok(text) => Ok(self.parse(&text)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(err) => Err(read_error(err.to_string())),
+        }
+    }
+
+    /// Skips lines that aren't a record, like one cut short by a crash,
+    /// rather than losing the whole history to them
+    fn parse(&self, text: &str) -> Vec<Record> {
+        text.lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .filter_map(|(number, line)| match serde_json::from_str(line) {
+                Ok(record) => Some(record),
+                Err(err) => {
+                    warn!(
+                        "Skipping line {} of {}: {}",
+                        number + 1,
+                        self.path.display(),
+                        err
+                    );
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// The last `count` runs, oldest first
+    pub fn last(&self, count: usize) -> Result<Vec<Record>, HistoryError> {
+        let mut runs = self.runs()?;
+        Ok(runs.split_off(runs.len().saturating_sub(count)))
+    }
+
+    pub fn get(&self, id: u32) -> Result<Option<Record>, HistoryError> {
+        Ok(self.runs()?.into_iter().find(|record| record.id == id))
+    }
+
+    /// Reply to `history N`
+    pub fn listing(&self, count: usize) -> String {
+        match self.last(count) {
+            Ok(runs) => Listing(runs).to_string(),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    /// Reply to `show ID`
+    pub fn show(&self, id: u32) -> String {
+        match self.get(id) {
+            Ok(Some(record)) => record.to_string(),
+            Ok(None) => format!("No run {} in the history", id),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    /// Keeps a copy of a stream that wasn't logged to a file
+    fn save_output(&self, id: u32, stream: &str, data: &[u8]) -> Option<PathBuf> {
+        let path = self.dir.join(format!("{}-{}.log", id, stream));
+        match fs::write(&path, data) {
+            Ok(()) => Some(path),
+            Err(err) => {
+                error!("Failed to save {}: {}", path.display(), err);
+                None
+            }
+        }
+    }
+
+    /// Adds a finished run to the history
+    pub fn record(
+        &self,
+        info: &CommandInfo,
+        parameters: BTreeMap<String, String>,
+        artifacts: &[PathBuf],
+    ) -> Result<Record, HistoryError> {
+        let write_error =
+            |err: String| HistoryError::WriteError(self.path.display().to_string(), err);
+        // Locked from reading the last id to appending, so that runs
+        // recorded at the same time by other processes get their own ids
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|err| write_error(err.to_string()))?;
+        lock(&file).map_err(|err| write_error(err.to_string()))?;
+        let mut text = String::new();
+        if let Err(err) = file.read_to_string(&mut text) {
+            return Err(HistoryError::ReadError(
+                self.path.display().to_string(),
+                err.to_string(),
+            ));
+        }
+        let id = self.parse(&text).last().map_or(1, |record| record.id + 1);
+        let start: DateTime<Local> = info.start.into();
+        let log = |stream: &str, log: &Option<PathBuf>, text: &str, binary: &Option<Vec<u8>>| match (
+            log, binary,
+        ) {
+            (Some(log), _) => Some(absolute(log)),
+            (None, Some(binary)) => self.save_output(id, stream, binary),
+            (None, None) => self.save_output(id, stream, text.as_bytes()),
+        };
+        let record = Record {
+            id,
+            command: info.command.clone(),
+            parameters,
+            start,
+            end: (info.start + info.time).into(),
+            time: info.time,
+            status: info.status.map(|status| status.to_string()),
+            exit_code: info.status.and_then(|status| status.code()),
+            success: info.status.is_some_and(|status| status.success()),
+            usage: info.usage,
+            attempts: info.attempts.len() + 1,
+            artifacts: artifacts.iter().map(|path| absolute(path)).collect(),
+            stdout_log: log(
+                "stdout",
+                &info.stdout_log,
+                &info.stdout,
+                &info.stdout_binary,
+            ),
+            stderr_log: log(
+                "stderr",
+                &info.stderr_log,
+                &info.stderr,
+                &info.stderr_binary,
+            ),
+        };
+
+        let mut line =
+            serde_json::to_string(&record).map_err(|err| write_error(err.to_string()))?;
+        // A run killed while writing leaves its line without an end, which
+        // would swallow this one
+        if !text.is_empty() && !text.ends_with('\n') {
+            line.insert(0, '\n');
+        }
+        writeln!(file, "{}", line).map_err(|err| write_error(err.to_string()))?;
+        Ok(record)
+    }
+}
+
+/// Takes an exclusive lock on the file, released once it's closed
+fn lock(file: &File) -> io::Result<()> {
+    match unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Paths are kept absolute so they still point at the file from elsewhere
+fn absolute(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_owned())
+}
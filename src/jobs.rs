@@ -0,0 +1,239 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs,
+    path::Path,
+    process::ExitStatus,
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+use thiserror::Error;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::alerts::Alert;
+use crate::config::Config;
+use crate::output::exit_status;
+use crate::progress::Progress;
+use crate::runner::{run, CommandInfo, Handle, JobCommand, RunnerError};
+use crate::schedule::Schedule;
+
+/// A finished run of the named job
+pub type Done = (String, Result<CommandInfo, RunnerError>);
+
+#[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum JobsError {
+    #[error("Failed to read jobs file {0}:\n {1}")]
+    ReadError(String, String),
+    #[error("Failed to parse jobs file {0}:\n {1}")]
+    ParseError(String, String),
+    #[error("Invalid job {0}: {1}")]
+    JobError(String, String),
+    #[error("Jobs aren't retried, set retries in the job section to 0 to use --jobs")]
+    RetriesError,
+    #[error("Jobs aren't scheduled, remove the cron and the runs in {0} to use --jobs")]
+    ScheduleError(String),
+}
+
+/// A command to run and the files to send after it
+pub struct JobSpec {
+    pub name: String,
+    pub command: JobCommand,
+    pub files: Vec<String>,
+    pub optional_files: Vec<String>,
+    pub only_new: bool,
+    pub cwd: Option<String>,
+    pub env: HashMap<String, String>,
+}
+
+impl JobSpec {
+    /// The config to run the job with, keeping its logs apart from other jobs
+    pub fn config(&self, config: &Config) -> Config {
+        let mut config = config.clone();
+        if self.cwd.is_some() {
+            config.job.cwd = self.cwd.clone();
+        }
+        config.job.env.extend(self.env.clone());
+        if let Some(dir) = &config.capture.log_dir {
+            let dir = Path::new(dir).join(&self.name);
+            config.capture.log_dir = Some(dir.display().to_string());
+        }
+        config
+    }
+}
+
+/// A job as written in the jobs file
+#[derive(Deserialize)]
+struct JobEntry {
+    name: String,
+    /// Command line to run through the shell
+    command: Option<String>,
+    /// Program and arguments to run without a shell
+    #[serde(default)]
+    argv: Vec<String>,
+    #[serde(default)]
+    files: Vec<String>,
+    #[serde(default)]
+    optional_files: Vec<String>,
+    #[serde(default)]
+    only_new: bool,
+    cwd: Option<String>,
+    #[serde(default)]
+    env: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct JobsFile {
+    job: Vec<JobEntry>,
+}
+
+/// Reads the jobs from a TOML file with a `[[job]]` table for each
+pub fn load(path: &str, config: &Config, schedule: &Schedule) -> Result<Vec<JobSpec>, JobsError> {
+    // Refused rather than ignored, jobs are only run once
+    if config.job.retries > 0 {
+        return Err(JobsError::RetriesError);
+    }
+    if schedule.next().is_some() {
+        return Err(JobsError::ScheduleError(config.schedule.file.clone()));
+    }
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) => return Err(JobsError::ReadError(path.to_owned(), err.to_string())),
+    };
+    let file: JobsFile = match toml::from_str(&text) {
+        Ok(file) => file,
+        Err(err) => return Err(JobsError::ParseError(path.to_owned(), err.to_string())),
+    };
+
+    let mut jobs: Vec<JobSpec> = Vec::new();
+    for entry in file.job {
+        let invalid = |why: &str| JobsError::JobError(entry.name.clone(), why.to_owned());
+        // Commands are lowercased, so names are matched without case
+        let name = entry.name.to_ascii_lowercase();
+        if name.is_empty() || name == "all" || name.contains(char::is_whitespace) {
+            return Err(invalid("names have to be one word other than \"all\""));
+        }
+        if jobs.iter().any(|job| job.name == name) {
+            return Err(invalid("the name is used by another job"));
+        }
+        let command = match (entry.command, entry.argv.is_empty()) {
+            (Some(line), true) => JobCommand::Shell(line),
+            (None, false) => JobCommand::Argv(entry.argv),
+            _ => return Err(invalid("give either command or argv")),
+        };
+        jobs.push(JobSpec {
+            name,
+            command,
+            files: entry.files,
+            optional_files: entry.optional_files,
+            only_new: entry.only_new,
+            cwd: entry.cwd,
+            env: entry.env,
+        });
+    }
+    Ok(jobs)
+}
+
+/// What a job is doing
+pub enum State {
+    Running {
+        start: Instant,
+        progress: Progress,
+        handle: Handle,
+    },
+    Finished {
+        status: Option<ExitStatus>,
+        time: Duration,
+    },
+    /// Couldn't be started
+    Failed(String),
+}
+
+pub struct Job {
+    pub spec: JobSpec,
+    pub state: State,
+}
+
+impl Job {
+    /// Starts the job in the background, sending its alerts to `alerts` and
+    /// the result to `done` once it's over
+    pub fn start(
+        &mut self,
+        config: &Config,
+        alerts: &UnboundedSender<Alert>,
+        done: &UnboundedSender<Done>,
+    ) -> Result<(), RunnerError> {
+        let progress = match Progress::new(&config.progress) {
+            Ok(progress) => progress,
+            Err((pattern, err)) => {
+                let err = RunnerError::PatternError(pattern, err.to_string());
+                self.state = State::Failed(err.to_string());
+                return Err(err);
+            }
+        };
+        let handle = Handle::default();
+        let name = self.spec.name.clone();
+        let command = self.spec.command.clone();
+        let job_config = self.spec.config(config);
+        let (alerts, done) = (alerts.clone(), done.clone());
+        let (job_progress, job_handle) = (progress.clone(), handle.clone());
+        tokio::task::spawn_blocking(move || {
+            let result = run(
+                &command,
+                &job_config,
+                Some(alerts),
+                Some(job_progress),
+                Some(job_handle),
+            );
+            // Nobody is waiting once we're done
+            let _ = done.send((name, result));
+        });
+        self.state = State::Running {
+            start: Instant::now(),
+            progress,
+            handle,
+        };
+        Ok(())
+    }
+
+    pub fn is_running(&self) -> bool {
+        matches!(self.state, State::Running { .. })
+    }
+
+    /// Kills the job if it's running
+    pub fn kill(&self) -> bool {
+        match &self.state {
+            State::Running { handle, .. } => handle.kill(),
+            _ => false,
+        }
+    }
+}
+
+impl Display for Job {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (\"{}\"): ", self.spec.name, self.spec.command)?;
+        match &self.state {
+            State::Running {
+                start, progress, ..
+            } => {
+                write!(f, "running for {}s", start.elapsed().as_secs())?;
+                if progress.snapshot().is_some() {
+                    write!(f, ", {}", progress)?;
+                }
+                Ok(())
+            }
+            State::Finished { status, time } => {
+                write!(f, "{} after {}s", exit_status(*status), time.as_secs())
+            }
+            State::Failed(err) => write!(f, "failed to start: {}", err),
+        }
+    }
+}
+
+/// Jobs matching a name from a command, every job for "all"
+pub fn select<'a>(jobs: &'a mut [Job], name: &str) -> Vec<&'a mut Job> {
+    jobs.iter_mut()
+        .filter(|job| name == "all" || job.spec.name == name)
+        .collect()
+}
@@ -0,0 +1,83 @@
+use std::io::{self, Write};
+
+use regex::Regex;
+
+/// Longest line kept, anything before is dropped
+const MAX_LINE: usize = 64 * 1024;
+
+/// Reads the output of the command a line at a time
+pub trait Lines {
+    /// A line without its end, `complete` is false for the part of a line
+    /// written so far
+    fn line(&mut self, line: &[u8], complete: bool);
+}
+
+/// Splits what's written to it into lines for `L`
+pub struct LineWriter<L: Lines> {
+    lines: L,
+    /// Bytes ending a line
+    ends: &'static [u8],
+    partial: Vec<u8>,
+}
+
+impl<L: Lines> LineWriter<L> {
+    pub fn new(lines: L, ends: &'static [u8]) -> Self {
+        Self {
+            lines,
+            ends,
+            partial: Vec::new(),
+        }
+    }
+}
+
+impl<L: Lines> Write for LineWriter<L> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut rest = buf;
+        while let Some(end) = rest.iter().position(|b| self.ends.contains(b)) {
+            self.partial.extend_from_slice(&rest[..end]);
+            let line = std::mem::take(&mut self.partial);
+            self.lines.line(&line, true);
+            rest = &rest[end + 1..];
+        }
+        self.partial.extend_from_slice(rest);
+        // Progress bars redraw a line for ever without ending it
+        if let Some(redraw) = self.partial.iter().rposition(|b| *b == b'\r') {
+            if redraw + 1 < self.partial.len() {
+                self.partial.drain(..=redraw);
+            }
+        }
+        if self.partial.len() > MAX_LINE {
+            self.partial.drain(..self.partial.len() - MAX_LINE);
+        }
+        if !self.partial.is_empty() {
+            self.lines.line(&self.partial, false);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<L: Lines> Drop for LineWriter<L> {
+    /// Reads the last line when the output doesn't end with a newline
+    fn drop(&mut self) {
+        if !self.partial.is_empty() {
+            let line = std::mem::take(&mut self.partial);
+            self.lines.line(&line, true);
+        }
+    }
+}
+
+/// Compiles the patterns, returning the one that failed if any does
+pub fn compile(patterns: &[String]) -> Result<Vec<Regex>, (String, regex::Error)> {
+    let mut regexes = Vec::new();
+    for pattern in patterns {
+        match Regex::new(pattern) {
+            Ok(regex) => regexes.push(regex),
+            Err(err) => return Err((pattern.clone(), err)),
+        }
+    }
+    Ok(regexes)
+}
@@ -1,50 +1,222 @@
 use backends::matrix_backend::MatrixBackend;
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
-use std::time::Duration;
-use tokio::time::sleep;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::time::{sleep, sleep_until, Instant};
 
 use backends::backend::{Backend, BackendCommand, BackendList, Sendable};
 use backends::smtp_email_backend::SmtpEmailBackend;
 
-use crate::config::Config;
-use crate::runner::run;
+use crate::artifacts::{collect, detect_mime};
+use crate::bench::Bench;
+use crate::config::{Config, OutputLimits};
+use crate::history::History;
+use crate::jobs::{select, Job, JobSpec, State};
+use crate::notify::Notifier;
+use crate::progress::Progress;
+use crate::runner::{backoff, run, should_retry, Attempt, CommandInfo, JobCommand, RunnerError};
+use crate::schedule::Schedule;
+use crate::sweep::Sweep;
+use crate::table::Table;
 
+mod alerts;
+mod ansi;
+mod artifacts;
 mod backends;
+mod bench;
+mod capture;
+mod chart;
 mod config;
+mod decode;
+mod diff;
+mod history;
+mod jobs;
+mod lines;
+mod metrics;
+mod notify;
+mod output;
+mod progress;
+mod pty;
+mod regression;
 mod runner;
+mod sampler;
+mod schedule;
+mod sweep;
+mod table;
+mod upload;
+mod usage;
+mod watch;
 
 #[derive(Parser, Debug)]
 #[command(author = "Luca Manolache", version = "0.1.0", about = "Run command controllable by email/text", long_about = None)]
+#[command(subcommand_negates_reqs = true)]
 struct Args {
     /// Location of the config file (default ./config.toml)
     #[arg(short, long, default_value = "./config.toml")]
     config: String,
 
     /// Backend to use (requires relevent section of config to be set)
-    #[arg(short = 'b', long = "backend")]
-    backend: BackendList,
+    #[arg(short = 'b', long = "backend", required = true)]
+    backend: Option<BackendList>,
 
+    /// Files to send after each run, as glob patterns (e.g. 'out/*.png')
     #[arg(short = 'f', long = "file")]
-    files: Option<Vec<String>>,
+    files: Vec<String>,
 
-    #[arg()]
-    command: String,
+    /// Like --file, but not reported when nothing matches
+    #[arg(short = 'o', long = "optional-file")]
+    optional_files: Vec<String>,
+
+    /// Only send files modified after the run started
+    #[arg(long = "only-new")]
+    only_new: bool,
+
+    /// Run the command under a pseudo-terminal, as if in an interactive shell
+    #[arg(long = "pty")]
+    pty: bool,
+
+    /// Shell to run the command with, e.g. "bash" or "zsh -lc"
+    #[arg(long = "shell")]
+    shell: Option<String>,
+
+    /// Command line to run through the shell
+    #[arg(required_unless_present_any = ["argv", "jobs"], conflicts_with = "argv")]
+    command: Option<String>,
+
+    /// Program and arguments to run without a shell, given after --
+    #[arg(last = true)]
+    argv: Vec<String>,
+
+    /// File of named jobs to run at the same time instead of one command
+    #[arg(long = "jobs", conflicts_with_all = ["command", "argv"])]
+    jobs: Option<String>,
+
+    #[command(subcommand)]
+    action: Option<Action>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Action {
+    /// Report on a process that's already running once it exits
+    Watch {
+        /// Pid of the process
+        #[arg(long, required_unless_present = "name", conflicts_with = "name")]
+        pid: Option<u32>,
+
+        /// Text in the command line of the process, like pgrep -f
+        #[arg(long)]
+        name: Option<String>,
+
+        /// Log files the process writes, sent as tails in the report
+        #[arg(short = 'l', long = "log")]
+        logs: Vec<String>,
+
+        /// Number of lines to send from each log
+        #[arg(long, default_value_t = 50)]
+        tail: usize,
+    },
+    /// List the last runs from the history
+    History {
+        /// Number of runs to list
+        #[arg(default_value_t = 10)]
+        count: usize,
+    },
+    /// Details of a run from the history
+    Show {
+        /// Id of the run, as listed by history
+        id: u32,
+    },
+    /// Run a command several times and report statistics of the runs
+    Bench {
+        /// Number of runs
+        #[arg(
+            short = 'n',
+            long = "runs",
+            default_value_t = 10,
+            value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+        )]
+        runs: usize,
+
+        /// Command line to run through the shell
+        #[arg(required_unless_present = "argv", conflicts_with = "argv")]
+        command: Option<String>,
+
+        /// Program and arguments to run without a shell, given after --
+        #[arg(last = true)]
+        argv: Vec<String>,
+    },
+    /// Run a command over every combination of the sweep parameters
+    Sweep {
+        /// Command line to run through the shell, {name} is replaced by
+        /// the value of parameter name
+        #[arg(required_unless_present = "argv", conflicts_with = "argv")]
+        command: Option<String>,
+
+        /// Program and arguments to run without a shell, given after --
+        #[arg(last = true)]
+        argv: Vec<String>,
+    },
+    /// Compare the output of two runs, by default the last two of the
+    /// same command
+    Diff {
+        /// The earlier run
+        a: Option<u32>,
+        /// The later run (default the last other one)
+        b: Option<u32>,
+    },
+}
+
+const HISTORY_DISABLED: &str = "The history is disabled, set enabled in the history section";
+
+impl Args {
+    fn job_command(&self) -> JobCommand {
+        match &self.command {
+            Some(line) => JobCommand::Shell(line.to_owned()),
+            None => JobCommand::Argv(self.argv.clone()),
+        }
+    }
+
+    fn job_spec(&self) -> JobSpec {
+        let command = self.job_command();
+        JobSpec {
+            name: command.to_string(),
+            command,
+            files: self.files.clone(),
+            optional_files: self.optional_files.clone(),
+            only_new: self.only_new,
+            cwd: None,
+            env: HashMap::new(),
+        }
+    }
 }
 
-async fn get_backend(backend: &BackendList, config: Config) -> Box<dyn Backend> {
+async fn get_backend(backend: &BackendList, config: &Config) -> Box<dyn Backend> {
     match backend {
         BackendList::Matrix => Box::new(
-            MatrixBackend::new(config.matrix.expect("Missing matrix section in config!"))
-                .await
-                .expect("Failed to create matrix backend!"),
+            MatrixBackend::new(
+                config
+                    .matrix
+                    .clone()
+                    .expect("Missing matrix section in config!"),
+                config.upload.as_ref().map(|upload| upload.max_size),
+            )
+            .await
+            .expect("Failed to create matrix backend!"),
         ),
         BackendList::Email => Box::new(
-            SmtpEmailBackend::new(config.email.expect("Missing email section in config!"))
-                .await
-                .expect("Failed to create email backend!"),
+            SmtpEmailBackend::new(
+                config
+                    .email
+                    .clone()
+                    .expect("Missing email section in config!"),
+            )
+            .await
+            .expect("Failed to create email backend!"),
         ),
-        _ => unimplemented!(),
     }
 }
 
@@ -63,6 +235,510 @@ async fn send(backend: &mut dyn Backend, msg: &Sendable) {
     }
 }
 
+/// Runs the command in the background, sending alerts from the watchers
+/// and answering `progress` while it runs, other commands are put off
+/// until it's done
+async fn run_watched(
+    backend: &mut dyn Backend,
+    command: JobCommand,
+    config: &Config,
+) -> Result<CommandInfo, RunnerError> {
+    let progress = match Progress::new(&config.progress) {
+        Ok(progress) => progress,
+        Err((pattern, err)) => return Err(RunnerError::PatternError(pattern, err.to_string())),
+    };
+    let (alerts, mut alerts_rx) = mpsc::unbounded_channel();
+    let name = command.to_string();
+    let job_config = config.clone();
+    let job_progress = progress.clone();
+    let mut job = tokio::task::spawn_blocking(move || {
+        run(
+            &command,
+            &job_config,
+            Some(alerts),
+            Some(job_progress),
+            None,
+        )
+    });
+    let result = loop {
+        tokio::select! {
+            Some(alert) = alerts_rx.recv() => send(backend, &Sendable::Alert(alert)).await,
+            command = backend.recieve() => {
+                let reply = match command.unwrap() {
+                    BackendCommand::Progress => format!("Progress of \"{}\": {}", name, progress),
+                    command => {
+                        backend.defer(command);
+                        format!("\"{}\" is still running, the command will be handled once it's done", name)
+                    }
+                };
+                send(backend, &Sendable::Raw(reply)).await;
+            }
+            result = &mut job => break result.expect("runner panicked"),
+        }
+    };
+    while let Ok(alert) = alerts_rx.try_recv() {
+        send(backend, &Sendable::Alert(alert)).await;
+    }
+    result
+}
+
+async fn run_and_report(
+    backend: &mut dyn Backend,
+    spec: &JobSpec,
+    config: &Config,
+    notifier: &mut Notifier,
+    history: Option<&History>,
+) {
+    let job = &config.job;
+    let mut attempts = Vec::new();
+    let mut first_start = None;
+    let info = loop {
+        let mut info = match run_watched(backend, spec.command.clone(), config).await {
+            Ok(info) => info,
+            Err(err) => {
+                send(backend, &Sendable::Raw(err.to_string())).await;
+                return;
+            }
+        };
+        first_start.get_or_insert(info.start);
+        info.attempts = attempts;
+        if info.attempts.len() as u32 >= job.retries || !should_retry(job, info.status) {
+            break info;
+        }
+
+        let backoff = backoff(job, info.attempts.len());
+        let notice = output::attempt_notice(&info, job.retries, backoff);
+        send(backend, &Sendable::Raw(notice)).await;
+        if !wait_to_retry(backend, &info.command, backoff).await {
+            break info;
+        }
+        attempts = info.attempts;
+        attempts.push(Attempt {
+            time: info.time,
+            status: info.status,
+        });
+    };
+    let since = spec.only_new.then_some(first_start.unwrap_or(info.start));
+    report(backend, info, spec, since, config, notifier, history).await;
+}
+
+/// Waits out the backoff before a retry, answering `progress` meanwhile and
+/// putting other commands off, false if `done` called the retry off
+async fn wait_to_retry(backend: &mut dyn Backend, name: &str, backoff: Duration) -> bool {
+    let retry_at = Instant::now() + backoff;
+    loop {
+        tokio::select! {
+            _ = sleep_until(retry_at) => return true,
+            command = backend.recieve() => {
+                let reply = match command.unwrap() {
+                    BackendCommand::Progress => format!(
+                        "\"{}\" failed, retrying in {}s",
+                        name,
+                        retry_at.saturating_duration_since(Instant::now()).as_secs()
+                    ),
+                    BackendCommand::Done => {
+                        backend.defer(BackendCommand::Done);
+                        return false;
+                    }
+                    command => {
+                        backend.defer(command);
+                        format!("\"{}\" is waiting to be retried, the command will be handled once it's done", name)
+                    }
+                };
+                send(backend, &Sendable::Raw(reply)).await;
+            }
+        }
+    }
+}
+
+/// Records the run in the history and sends its report and artifacts, if
+/// the notify rules let it
+async fn report(
+    backend: &mut dyn Backend,
+    mut info: CommandInfo,
+    spec: &JobSpec,
+    since: Option<SystemTime>,
+    config: &Config,
+    notifier: &mut Notifier,
+    history: Option<&History>,
+) {
+    let required = collect(&spec.files, since);
+    let optional = collect(&spec.optional_files, since);
+    if let Some(history) = history {
+        let parameters = config.job.env.clone().into_iter().collect();
+        if let Some(regression) = &config.regression {
+            match history.runs() {
+                Ok(runs) => {
+                    info.deviations = regression::compare(&info, &parameters, &runs, regression)
+                }
+                Err(err) => send(backend, &Sendable::Raw(err.to_string())).await,
+            }
+        }
+        let artifacts: Vec<PathBuf> = required
+            .found
+            .iter()
+            .chain(optional.found.iter())
+            .cloned()
+            .collect();
+        if let Err(err) = history.record(&info, parameters, &artifacts) {
+            send(backend, &Sendable::Raw(err.to_string())).await;
+        }
+    }
+    if !notifier.should_notify(&info) {
+        return;
+    }
+
+    let mut extras = output::full_logs(&info, backend.limits());
+    if !info.samples.is_empty() {
+        extras.extend(sampler::attachments(&info.samples));
+    }
+    if !info.metrics.is_empty() {
+        extras.extend(info.metrics.attachments());
+    }
+    send(backend, &Sendable::CommandInfo(Box::new(info))).await;
+    for extra in &extras {
+        send(backend, extra).await;
+    }
+
+    if !required.missing.is_empty() {
+        send(backend, &Sendable::Raw(required.to_string())).await;
+    }
+    for path in required.found.iter().chain(optional.found.iter()) {
+        let name = path.display().to_string();
+        let file = match fs::read(path) {
+            Ok(file) => file,
+            Err(err) => {
+                send(
+                    backend,
+                    &Sendable::Raw(format!("Failed to read artifact {}: {}", name, err)),
+                )
+                .await;
+                continue;
+            }
+        };
+
+        let mime = detect_mime(path, &file, &config.mime);
+        if config.tables.enabled && mime.essence_str() == "text/csv" {
+            if let Some(table) = Table::parse(name.clone(), &file, &config.tables) {
+                send(backend, &Sendable::Table(table)).await;
+            }
+        }
+        send(backend, &Sendable::attachment(mime, name, file)).await;
+    }
+}
+
+/// Runs the command `runs` times and sends one report of them all, or of
+/// the runs done before one failed to start
+async fn run_bench(
+    backend: &mut dyn Backend,
+    command: JobCommand,
+    runs: usize,
+    config: &Config,
+    history: Option<&History>,
+) {
+    let mut bench = Bench {
+        command: command.to_string(),
+        runs: Vec::new(),
+    };
+    for run in 1..=runs {
+        println!("Benchmark run {}/{}", run, runs);
+        let info = match run_watched(backend, command.clone(), config).await {
+            Ok(info) => info,
+            Err(err) => {
+                let notice = format!(
+                    "Benchmark stopped after {} of {} runs:\n{}",
+                    bench.runs.len(),
+                    runs,
+                    err
+                );
+                send(backend, &Sendable::Raw(notice)).await;
+                break;
+            }
+        };
+        if let Some(history) = history {
+            let parameters = config.job.env.clone().into_iter().collect();
+            if let Err(err) = history.record(&info, parameters, &[]) {
+                send(backend, &Sendable::Raw(err.to_string())).await;
+            }
+        }
+        bench.runs.push(info);
+    }
+    if bench.runs.is_empty() {
+        return;
+    }
+    for report in &bench.report() {
+        send(backend, report).await;
+    }
+}
+
+/// Runs the command over the sweep, a few runs at a time, sending a notice
+/// for each failed run and a table of the results at the end
+async fn run_sweep(
+    backend: &mut dyn Backend,
+    command: JobCommand,
+    config: &Config,
+    history: Option<&History>,
+) {
+    let sweep = match Sweep::new(config.sweep.as_ref()) {
+        Ok(sweep) => sweep,
+        Err(err) => {
+            send(backend, &Sendable::Raw(err.to_string())).await;
+            return;
+        }
+    };
+    let parallel = config
+        .sweep
+        .as_ref()
+        .map_or(1, |sweep| sweep.parallel.max(1));
+    let limit = Arc::new(Semaphore::new(parallel));
+    let (alerts, mut alerts_rx) = mpsc::unbounded_channel();
+    let (done, mut done_rx) = mpsc::unbounded_channel();
+    for (index, point) in sweep.points.clone().into_iter().enumerate() {
+        let job_config = Sweep::config(config, index, &point);
+        let job_command = Sweep::command(&command, &point);
+        let job_name = job_command.to_string();
+        let (limit, alerts, done) = (limit.clone(), alerts.clone(), done.clone());
+        tokio::spawn(async move {
+            let _permit = limit.acquire_owned().await;
+            let result = tokio::task::spawn_blocking(move || {
+                run(&job_command, &job_config, Some(alerts), None, None)
+            })
+            .await;
+            let result = match result {
+                Ok(result) => result,
+                Err(err) => Err(RunnerError::RuntimeError(job_name, err.to_string())),
+            };
+            let _ = done.send((point, result));
+        });
+    }
+    drop(alerts);
+    drop(done);
+
+    let name = command.to_string();
+    let total = sweep.points.len();
+    let mut results = sweep.results(name.clone());
+    loop {
+        tokio::select! {
+            Some(alert) = alerts_rx.recv() => send(backend, &Sendable::Alert(alert)).await,
+            done = done_rx.recv() => {
+                // Every run has sent its result
+                let Some((point, result)) = done else {
+                    break;
+                };
+                let info = match result {
+                    Ok(info) => info,
+                    Err(err) => {
+                        send(backend, &Sendable::Raw(sweep::error_notice(&point, &err))).await;
+                        sweep.add_error(&mut results, point, &err);
+                        continue;
+                    }
+                };
+                if let Some(history) = history {
+                    let mut parameters: BTreeMap<String, String> =
+                        config.job.env.clone().into_iter().collect();
+                    parameters.extend(point.clone());
+                    if let Err(err) = history.record(&info, parameters, &[]) {
+                        send(backend, &Sendable::Raw(err.to_string())).await;
+                    }
+                }
+                if !info.status.is_some_and(|status| status.success()) {
+                    let notice = sweep::failure_notice(&point, &info);
+                    send(backend, &Sendable::Raw(notice)).await;
+                }
+                sweep.add(&mut results, point, &info);
+            }
+            command = backend.recieve() => {
+                let reply = match command.unwrap() {
+                    BackendCommand::Progress => format!(
+                        "Sweep of \"{}\": {}/{} runs done", name, results.len(), total
+                    ),
+                    command => {
+                        backend.defer(command);
+                        format!(
+                            "Sweep of \"{}\" is running, the command will be handled once it's done",
+                            name
+                        )
+                    }
+                };
+                send(backend, &Sendable::Raw(reply)).await;
+            }
+        }
+    }
+    while let Ok(alert) = alerts_rx.try_recv() {
+        send(backend, &Sendable::Alert(alert)).await;
+    }
+    for report in &results.report(&config.tables) {
+        send(backend, report).await;
+    }
+}
+
+/// Replies to the commands reading the history
+fn history_replies(
+    command: &BackendCommand,
+    history: Option<&History>,
+    config: &Config,
+    limits: &OutputLimits,
+) -> Vec<Sendable> {
+    let Some(history) = history else {
+        return vec![Sendable::Raw(HISTORY_DISABLED.to_owned())];
+    };
+    match command {
+        BackendCommand::History(count) => vec![Sendable::Raw(history.listing(*count))],
+        BackendCommand::Show(id) => vec![Sendable::Raw(history.show(*id))],
+        BackendCommand::Diff(a, b) => match diff::diff(history, *a, *b, &config.diff) {
+            Ok(diff) => diff.into_sendables(limits),
+            Err(err) => vec![Sendable::Raw(err.to_string())],
+        },
+        _ => Vec::new(),
+    }
+}
+
+/// Runs the jobs side by side, reporting on each as it finishes and
+/// answering commands addressed to them by name
+async fn run_jobs(
+    backend: &mut dyn Backend,
+    specs: Vec<JobSpec>,
+    config: &Config,
+    notifier: &mut Notifier,
+    history: Option<&History>,
+    cat: &[u8],
+) {
+    let (alerts, mut alerts_rx) = mpsc::unbounded_channel();
+    let (done, mut done_rx) = mpsc::unbounded_channel();
+    let mut jobs: Vec<Job> = specs
+        .into_iter()
+        .map(|spec| Job {
+            spec,
+            state: State::Failed("not started".to_owned()),
+        })
+        .collect();
+    for job in &mut jobs {
+        if let Err(err) = job.start(config, &alerts, &done) {
+            let reply = format!("Job {} failed to start: {}", job.spec.name, err);
+            send(backend, &Sendable::Raw(reply)).await;
+        }
+    }
+
+    loop {
+        tokio::select! {
+            Some(alert) = alerts_rx.recv() => send(backend, &Sendable::Alert(alert)).await,
+            Some((name, result)) = done_rx.recv() => {
+                let Some(job) = jobs.iter_mut().find(|job| job.spec.name == name) else {
+                    continue;
+                };
+                let mut info = match result {
+                    Ok(info) => info,
+                    Err(err) => {
+                        job.state = State::Failed(err.to_string());
+                        send(backend, &Sendable::Raw(format!("Job {}: {}", name, err))).await;
+                        continue;
+                    }
+                };
+                job.state = State::Finished {
+                    status: info.status,
+                    time: info.time,
+                };
+                info.command = format!("[{}] {}", name, info.command);
+                let since = job.spec.only_new.then_some(info.start);
+                let job_config = job.spec.config(config);
+                report(backend, info, &job.spec, since, &job_config, notifier, history).await;
+            }
+            _ = schedule::sleep_until(notifier.next_digest()) => {
+                if let Some(digest) = notifier.take_digest() {
+                    send(backend, &Sendable::Raw(digest.to_string())).await;
+                }
+            }
+            command = backend.recieve() => {
+                let command = command.unwrap();
+                let replies = match &command {
+                    BackendCommand::Done => {
+                        for job in &jobs {
+                            job.kill();
+                        }
+                        send(backend, &Sendable::Raw("Done!".to_string())).await;
+                        return;
+                    }
+                    BackendCommand::Rerun | BackendCommand::RerunJob(_) => {
+                        let name = match &command {
+                            BackendCommand::RerunJob(name) => name.as_str(),
+                            _ => "all",
+                        };
+                        let mut started = Vec::new();
+                        let mut replies = Vec::new();
+                        for job in select(&mut jobs, name) {
+                            if !job.is_running() {
+                                match job.start(config, &alerts, &done) {
+                                    Ok(()) => started.push(job.spec.name.clone()),
+                                    Err(err) => replies.push(Sendable::Raw(format!(
+                                        "Job {} failed to start: {}",
+                                        job.spec.name, err
+                                    ))),
+                                }
+                            }
+                        }
+                        if !started.is_empty() {
+                            replies.push(Sendable::Raw(format!("Started {}", started.join(", "))));
+                        } else if replies.is_empty() {
+                            replies.push(Sendable::Raw(format!(
+                                "No job {} to rerun, it's running or unknown",
+                                name
+                            )));
+                        }
+                        replies
+                    }
+                    BackendCommand::Kill(name) => {
+                        let killed: Vec<String> = select(&mut jobs, name)
+                            .into_iter()
+                            .filter(|job| job.kill())
+                            .map(|job| job.spec.name.clone())
+                            .collect();
+                        let reply = match killed.is_empty() {
+                            true => format!("No job {} is running", name),
+                            false => format!("Killed {}", killed.join(", ")),
+                        };
+                        vec![Sendable::Raw(reply)]
+                    }
+                    BackendCommand::Progress | BackendCommand::Status(_) => {
+                        let name = match &command {
+                            BackendCommand::Status(Some(name)) => name.as_str(),
+                            _ => "all",
+                        };
+                        let status: Vec<String> = select(&mut jobs, name)
+                            .into_iter()
+                            .map(|job| job.to_string())
+                            .collect();
+                        let reply = match status.is_empty() {
+                            true => format!("No job {}", name),
+                            false => status.join("\n"),
+                        };
+                        vec![Sendable::Raw(reply)]
+                    }
+                    BackendCommand::Cat => vec![Sendable::Image((
+                        mime::IMAGE_JPEG,
+                        "cat".to_string(),
+                        cat.to_vec(),
+                    ))],
+                    BackendCommand::History(_)
+                    | BackendCommand::Show(_)
+                    | BackendCommand::Diff(_, _) => {
+                        history_replies(&command, history, config, backend.limits())
+                    }
+                    BackendCommand::UnkownCommand(s) => {
+                        vec![Sendable::Raw(format!("Unkown command: {}", s))]
+                    }
+                    _ => vec![Sendable::Raw(
+                        "Not supported with --jobs, use rerun, kill or status with a job name"
+                            .to_string(),
+                    )],
+                };
+                for reply in &replies {
+                    send(backend, reply).await;
+                }
+            }
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() {
     pretty_env_logger::init();
@@ -70,45 +746,189 @@ async fn main() {
     let args = Args::parse();
 
     let config = fs::read_to_string("./config.toml").expect("Failed to open config");
-    let config: Config = toml::from_str(&config).expect("Failed to parse config");
+    let mut config: Config = toml::from_str(&config).expect("Failed to parse config");
+    config.capture.pty |= args.pty;
+    if args.shell.is_some() {
+        config.job.shell = args.shell.clone();
+    }
 
-    let mut backend = get_backend(&args.backend, config).await;
+    let history = match config.history.enabled {
+        true => match History::open(&config.history) {
+            Ok(history) => Some(history),
+            Err(err) => panic!("{}", err),
+        },
+        false => None,
+    };
 
-    let mut command = BackendCommand::Rerun;
+    match &args.action {
+        Some(Action::History { count }) => {
+            match &history {
+                Some(history) => println!("{}", history.listing(*count)),
+                None => println!("{}", HISTORY_DISABLED),
+            }
+            return;
+        }
+        Some(Action::Show { id }) => {
+            match &history {
+                Some(history) => println!("{}", history.show(*id)),
+                None => println!("{}", HISTORY_DISABLED),
+            }
+            return;
+        }
+        Some(Action::Diff { a, b }) => {
+            match &history {
+                Some(history) => match diff::diff(history, *a, *b, &config.diff) {
+                    Ok(diff) => println!("{}", diff),
+                    Err(err) => println!("{}", err),
+                },
+                None => println!("{}", HISTORY_DISABLED),
+            }
+            return;
+        }
+        _ => {}
+    }
 
-    let cat = fs::read("./cat.jpeg").expect("Can't open image file.");
+    let backend = args.backend.as_ref().expect("Missing --backend");
+    let mut backend = get_backend(backend, &config).await;
 
-    loop {
-        if command == BackendCommand::Rerun {
-            let info = run(&args.command).unwrap();
-            send(&mut *backend, &Sendable::CommandInfo(info)).await;
-            if args.files.is_some() {
-                for name in args.files.as_ref().expect("Something impossible happened") {
-                    let file = fs::read(name).expect(&format!("File {name} doesn't exist"));
-
-                    let parts: Vec<&str> = name.split('.').collect();
-                    let res = match parts.last() {
-                        Some(v) => match *v {
-                            "png" => mime::IMAGE_PNG,
-                            "jpg" => mime::IMAGE_JPEG,
-                            "jpeg" => mime::IMAGE_JPEG,
-                            "json" => mime::APPLICATION_JSON,
-                            "csv" => mime::TEXT_CSV,
-                            &_ => mime::TEXT_PLAIN,
-                        },
-                        None => mime::TEXT_PLAIN,
-                    };
+    if let Some(Action::Bench {
+        runs,
+        command,
+        argv,
+    }) = &args.action
+    {
+        let command = match command {
+            Some(line) => JobCommand::Shell(line.to_owned()),
+            None => JobCommand::Argv(argv.clone()),
+        };
+        run_bench(&mut *backend, command, *runs, &config, history.as_ref()).await;
+        return;
+    }
 
-                    send(
-                        &mut *backend,
-                        &Sendable::Image((res, name.to_string(), file)),
-                    )
-                    .await;
+    if let Some(Action::Sweep { command, argv }) = &args.action {
+        let command = match command {
+            Some(line) => JobCommand::Shell(line.to_owned()),
+            None => JobCommand::Argv(argv.clone()),
+        };
+        run_sweep(&mut *backend, command, &config, history.as_ref()).await;
+        return;
+    }
+
+    if let Some(Action::Watch {
+        pid,
+        name,
+        logs,
+        tail,
+    }) = &args.action
+    {
+        let (pid, command) = match watch::find(*pid, name.as_deref()) {
+            Ok(found) => found,
+            Err(err) => {
+                send(&mut *backend, &Sendable::Raw(err.to_string())).await;
+                return;
+            }
+        };
+        println!("Watching {} ({})", pid, command);
+        let (logs, tail, name) = (logs.clone(), *tail, command.clone());
+        let mut watching =
+            tokio::task::spawn_blocking(move || watch::watch(pid, command, &logs, tail));
+        let start = SystemTime::now();
+        let watched = loop {
+            tokio::select! {
+                watched = &mut watching => break watched.expect("watcher panicked"),
+                command = backend.recieve() => {
+                    // Nothing is left to handle commands once the process exits
+                    let reply = match command.unwrap() {
+                        BackendCommand::Done => {
+                            send(&mut *backend, &Sendable::Raw("Done!".to_string())).await;
+                            return;
+                        }
+                        BackendCommand::Progress => format!(
+                            "Watching {} (\"{}\") for {}s",
+                            pid,
+                            name,
+                            start.elapsed().unwrap_or_default().as_secs()
+                        ),
+                        _ => format!(
+                            "Watching {} (\"{}\"), only progress and done are answered",
+                            pid, name
+                        ),
+                    };
+                    send(&mut *backend, &Sendable::Raw(reply)).await;
                 }
             }
+        };
+        match watched {
+            Ok(info) => send(&mut *backend, &Sendable::CommandInfo(Box::new(info))).await,
+            Err(err) => send(&mut *backend, &Sendable::Raw(err.to_string())).await,
         }
+        return;
+    }
 
-        command = backend.recieve().await.unwrap();
+    let mut schedule = match Schedule::load(&config.schedule) {
+        Ok(schedule) => schedule,
+        Err(err) => panic!("{}", err),
+    };
+
+    let mut notifier = match Notifier::load(&config.notify) {
+        Ok(notifier) => notifier,
+        Err(err) => panic!("{}", err),
+    };
+
+    let cat = fs::read("./cat.jpeg").expect("Can't open image file.");
+
+    if let Some(path) = &args.jobs {
+        let specs = match jobs::load(path, &config, &schedule) {
+            Ok(specs) => specs,
+            Err(err) => panic!("{}", err),
+        };
+        run_jobs(
+            &mut *backend,
+            specs,
+            &config,
+            &mut notifier,
+            history.as_ref(),
+            &cat,
+        )
+        .await;
+        return;
+    }
+
+    let spec = args.job_spec();
+    let mut command = BackendCommand::Rerun;
+
+    loop {
+        if command == BackendCommand::Rerun {
+            run_and_report(
+                &mut *backend,
+                &spec,
+                &config,
+                &mut notifier,
+                history.as_ref(),
+            )
+            .await;
+        }
+
+        // Commands sent during the run first, then whichever comes first, a
+        // command or a scheduled run
+        command = match backend.deferred() {
+            Some(command) => command,
+            None => loop {
+                tokio::select! {
+                    command = backend.recieve() => break command.unwrap(),
+                    _ = schedule::sleep_until(schedule.next()) => {
+                        if schedule.take_due() {
+                            break BackendCommand::Rerun;
+                        }
+                    }
+                    _ = schedule::sleep_until(notifier.next_digest()) => {
+                        if let Some(digest) = notifier.take_digest() {
+                            send(&mut *backend, &Sendable::Raw(digest.to_string())).await;
+                        }
+                    }
+                }
+            },
+        };
         match &command {
             BackendCommand::Rerun => continue,
             BackendCommand::Done => {
@@ -129,6 +949,100 @@ async fn main() {
                 )
                 .await
             }
+            BackendCommand::Upload(files) => {
+                let Some(upload) = &config.upload else {
+                    send(
+                        &mut *backend,
+                        &Sendable::Raw(
+                            "Uploads are disabled, add an upload section to the config".to_string(),
+                        ),
+                    )
+                    .await;
+                    continue;
+                };
+                let rerun = match upload::save(upload, files) {
+                    Ok(report) => {
+                        send(&mut *backend, &Sendable::Raw(report.to_string())).await;
+                        upload.rerun && report.any_saved()
+                    }
+                    Err(err) => {
+                        send(
+                            &mut *backend,
+                            &Sendable::Raw(format!("Failed to save upload: {}", err)),
+                        )
+                        .await;
+                        false
+                    }
+                };
+                if rerun {
+                    command = BackendCommand::Rerun;
+                }
+            }
+            BackendCommand::UploadRejected(name, reason) => {
+                send(
+                    &mut *backend,
+                    &Sendable::Raw(format!("Rejected upload {} ({})", name, reason)),
+                )
+                .await
+            }
+            BackendCommand::RerunAt(at) => {
+                let id = schedule.add(*at);
+                send(
+                    &mut *backend,
+                    &Sendable::Raw(format!(
+                        "Scheduled run {} at {}",
+                        id,
+                        at.format("%Y-%m-%d %H:%M")
+                    )),
+                )
+                .await
+            }
+            BackendCommand::Progress => {
+                send(
+                    &mut *backend,
+                    &Sendable::Raw("Nothing is running".to_string()),
+                )
+                .await
+            }
+            BackendCommand::Schedule => {
+                send(&mut *backend, &Sendable::Raw(schedule.to_string())).await
+            }
+            BackendCommand::History(_) | BackendCommand::Show(_) | BackendCommand::Diff(_, _) => {
+                let replies =
+                    history_replies(&command, history.as_ref(), &config, backend.limits());
+                for reply in &replies {
+                    send(&mut *backend, reply).await;
+                }
+            }
+            BackendCommand::RerunJob(_) | BackendCommand::Status(_) | BackendCommand::Kill(_) => {
+                send(
+                    &mut *backend,
+                    &Sendable::Raw("Jobs are only named with --jobs".to_string()),
+                )
+                .await
+            }
+            BackendCommand::Bench(runs) => {
+                run_bench(
+                    &mut *backend,
+                    args.job_command(),
+                    *runs,
+                    &config,
+                    history.as_ref(),
+                )
+                .await
+            }
+            BackendCommand::Sweep => {
+                run_sweep(&mut *backend, args.job_command(), &config, history.as_ref()).await
+            }
+            BackendCommand::Cancel(id) => {
+                let cancelled = schedule.cancel(*id);
+                let msg = match id {
+                    Some(id) if cancelled == 0 => format!("No scheduled run {}", id),
+                    Some(id) => format!("Cancelled run {}", id),
+                    None => format!("Cancelled {} scheduled runs", cancelled),
+                };
+                send(&mut *backend, &Sendable::Raw(msg)).await
+            }
         }
     }
 }
@@ -0,0 +1,183 @@
+use std::{
+    fmt::Write as _,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use regex::Regex;
+
+use crate::ansi::strip;
+use crate::backends::backend::Sendable;
+use crate::chart::{line_chart, Panel, Series};
+use crate::lines::{compile, LineWriter, Lines};
+
+/// Values read from one line of output
+pub struct Row {
+    /// The step, or seconds since the start when the patterns have no step
+    pub x: f64,
+    pub values: Vec<(String, f64)>,
+}
+
+/// Metrics read from the output over the run
+#[derive(Default)]
+pub struct Metrics {
+    pub x_label: String,
+    /// Metric names in the order they were first seen
+    pub names: Vec<String>,
+    pub rows: Vec<Row>,
+}
+
+impl Metrics {
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    pub fn to_csv(&self) -> String {
+        let mut csv = self.x_label.clone();
+        for name in &self.names {
+            csv.push(',');
+            csv.push_str(name);
+        }
+        csv.push('\n');
+        for row in &self.rows {
+            let _ = write!(csv, "{}", row.x);
+            for name in &self.names {
+                csv.push(',');
+                if let Some((_, value)) = row.values.iter().find(|(n, _)| n == name) {
+                    let _ = write!(csv, "{}", value);
+                }
+            }
+            csv.push('\n');
+        }
+        csv
+    }
+
+    /// A chart with a panel per metric and the values as CSV
+    pub fn attachments(&self) -> Vec<Sendable> {
+        let panels: Vec<Panel> = self
+            .names
+            .iter()
+            .map(|name| Panel {
+                title: name.clone(),
+                series: vec![Series {
+                    name: name.clone(),
+                    points: self
+                        .rows
+                        .iter()
+                        .filter_map(|row| {
+                            let (_, value) = row.values.iter().find(|(n, _)| n == name)?;
+                            Some((row.x, *value))
+                        })
+                        .collect(),
+                }],
+            })
+            .collect();
+
+        let mut attachments = Vec::new();
+        match line_chart(&self.x_label, &panels) {
+            Ok(png) => attachments.push(Sendable::Image((
+                mime::IMAGE_PNG,
+                "metrics.png".to_owned(),
+                png,
+            ))),
+            Err(err) => attachments.push(Sendable::Raw(err.to_string())),
+        }
+        attachments.push(Sendable::File((
+            mime::TEXT_CSV,
+            "metrics.csv".to_owned(),
+            self.to_csv().into_bytes(),
+        )));
+        attachments
+    }
+}
+
+/// Reads metrics from the output with regexes, each named group other than
+/// `step` being a metric
+#[derive(Clone)]
+pub struct Extractor {
+    patterns: Arc<Vec<Regex>>,
+    start: Instant,
+    metrics: Arc<Mutex<Metrics>>,
+}
+
+impl Extractor {
+    pub fn new(patterns: &[String]) -> Result<Self, (String, regex::Error)> {
+        let regexes = compile(patterns)?;
+        let by_step = regexes
+            .iter()
+            .all(|regex| regex.capture_names().any(|name| name == Some("step")));
+        let metrics = Metrics {
+            x_label: if by_step { "step" } else { "seconds" }.to_owned(),
+            ..Default::default()
+        };
+        Ok(Self {
+            patterns: Arc::new(regexes),
+            start: Instant::now(),
+            metrics: Arc::new(Mutex::new(metrics)),
+        })
+    }
+
+    fn update(&self, line: &str) {
+        let line = strip(line);
+        let mut metrics = self.metrics.lock().expect("metrics lock poisoned");
+        let by_step = metrics.x_label == "step";
+        for regex in self.patterns.iter() {
+            let Some(captures) = regex.captures(&line) else {
+                continue;
+            };
+            let x = if by_step {
+                match captures
+                    .name("step")
+                    .map(|step| step.as_str().parse::<f64>())
+                {
+                    Some(Ok(step)) if step.is_finite() => step,
+                    _ => continue,
+                }
+            } else {
+                self.start.elapsed().as_secs_f64()
+            };
+            let values: Vec<(String, f64)> = regex
+                .capture_names()
+                .flatten()
+                .filter(|name| *name != "step")
+                .filter_map(|name| {
+                    let value = captures.name(name)?.as_str().parse::<f64>().ok()?;
+                    // "inf" and "nan" parse too but can't be drawn
+                    value.is_finite().then(|| (name.to_owned(), value))
+                })
+                .collect();
+            if values.is_empty() {
+                continue;
+            }
+            for (name, _) in &values {
+                if !metrics.names.contains(name) {
+                    metrics.names.push(name.clone());
+                }
+            }
+            metrics.rows.push(Row { x, values });
+        }
+    }
+
+    /// A writer reading metrics from one stream
+    pub fn stream(&self) -> LineWriter<Extractor> {
+        LineWriter::new(self.clone(), b"\n")
+    }
+
+    /// The metrics read so far, leaving none behind
+    pub fn take(&self) -> Metrics {
+        let mut metrics = self.metrics.lock().expect("metrics lock poisoned");
+        Metrics {
+            x_label: metrics.x_label.clone(),
+            names: std::mem::take(&mut metrics.names),
+            rows: std::mem::take(&mut metrics.rows),
+        }
+    }
+}
+
+impl Lines for Extractor {
+    fn line(&mut self, line: &[u8], complete: bool) {
+        if complete {
+            self.update(&String::from_utf8_lossy(line));
+        }
+    }
+}
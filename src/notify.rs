@@ -0,0 +1,272 @@
+use std::{fmt::Display, fs, io, path::PathBuf, time::Duration};
+
+use chrono::{DateTime, Local, NaiveTime, TimeZone};
+use log::*;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::config::NotifyConfig;
+use crate::output::exit_status;
+use crate::runner::CommandInfo;
+use crate::schedule::parse_duration;
+
+#[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum NotifyError {
+    #[error("Invalid notify_on rule {0}:\n {1}")]
+    RuleError(String, String),
+    #[error("Invalid digest_at time {0}")]
+    DigestTimeError(String),
+    #[error("Failed to read digest {0}:\n {1}")]
+    LoadError(String, String),
+}
+
+enum Stream {
+    Stdout,
+    Stderr,
+    Output,
+}
+
+/// When to send the report of a run
+enum Rule {
+    Always,
+    Success,
+    Failure,
+    LongerThan(Duration),
+    ShorterThan(Duration),
+    Matches(Stream, Regex),
+}
+
+impl Rule {
+    fn parse(rule: &str) -> Result<Self, NotifyError> {
+        let invalid = |why: &str| NotifyError::RuleError(rule.to_owned(), why.to_owned());
+        let rule = rule.trim();
+        match rule {
+            "always" => return Ok(Rule::Always),
+            "success" => return Ok(Rule::Success),
+            "failure" => return Ok(Rule::Failure),
+            _ => {}
+        }
+
+        if let Some(rest) = rule.strip_prefix("duration") {
+            let (longer, limit) = match (rest.strip_prefix('>'), rest.strip_prefix('<')) {
+                (Some(limit), _) => (true, limit),
+                (_, Some(limit)) => (false, limit),
+                _ => return Err(invalid("expected duration>TIME or duration<TIME")),
+            };
+            let Some(limit) = parse_duration(limit) else {
+                return Err(invalid("expected a time like 30m or 1h30m"));
+            };
+            return Ok(if longer {
+                Rule::LongerThan(limit)
+            } else {
+                Rule::ShorterThan(limit)
+            });
+        }
+
+        let Some((stream, pattern)) = rule.split_once('~') else {
+            return Err(invalid("unknown rule"));
+        };
+        let stream = match stream {
+            "stdout" => Stream::Stdout,
+            "stderr" => Stream::Stderr,
+            "output" => Stream::Output,
+            _ => return Err(invalid("expected stdout, stderr or output before ~")),
+        };
+        let Some(pattern) = pattern
+            .strip_prefix('/')
+            .and_then(|pattern| pattern.strip_suffix('/'))
+        else {
+            return Err(invalid("the pattern has to be between slashes"));
+        };
+        match Regex::new(pattern) {
+            Ok(regex) => Ok(Rule::Matches(stream, regex)),
+            Err(err) => Err(invalid(&err.to_string())),
+        }
+    }
+
+    fn matches(&self, info: &CommandInfo) -> bool {
+        let success = info.status.is_some_and(|status| status.success());
+        match self {
+            Rule::Always => true,
+            Rule::Success => success,
+            Rule::Failure => !success,
+            Rule::LongerThan(limit) => info.time > *limit,
+            Rule::ShorterThan(limit) => info.time < *limit,
+            Rule::Matches(Stream::Stdout, regex) => regex.is_match(&info.stdout),
+            Rule::Matches(Stream::Stderr, regex) => regex.is_match(&info.stderr),
+            Rule::Matches(Stream::Output, regex) => {
+                regex.is_match(&info.stdout) || regex.is_match(&info.stderr)
+            }
+        }
+    }
+}
+
+/// A run that wasn't reported
+#[derive(Serialize, Deserialize)]
+struct Suppressed {
+    command: String,
+    start: DateTime<Local>,
+    time: Duration,
+    status: String,
+}
+
+/// Decides which runs are reported, and keeps the rest for a daily digest
+pub struct Notifier {
+    rules: Vec<Rule>,
+    digest_at: NaiveTime,
+    path: PathBuf,
+    suppressed: Vec<Suppressed>,
+}
+
+impl Notifier {
+    pub fn load(config: &NotifyConfig) -> Result<Self, NotifyError> {
+        let rules = config
+            .notify_on
+            .iter()
+            .map(|rule| Rule::parse(rule))
+            .collect::<Result<Vec<Rule>, NotifyError>>()?;
+        let digest_at = match NaiveTime::parse_from_str(&config.digest_at, "%H:%M") {
+            Ok(time) => time,
+            Err(_) => return Err(NotifyError::DigestTimeError(config.digest_at.clone())),
+        };
+
+        let path = PathBuf::from(&config.digest_file);
+        let suppressed = match fs::read_to_string(&path) {
+            Ok(text) => match serde_json::from_str(&text) {
+                Ok(suppressed) => suppressed,
+                Err(err) => {
+                    return Err(NotifyError::LoadError(
+                        config.digest_file.clone(),
+                        err.to_string(),
+                    ))
+                }
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => {
+                return Err(NotifyError::LoadError(
+                    config.digest_file.clone(),
+                    err.to_string(),
+                ))
+            }
+        };
+
+        Ok(Self {
+            rules,
+            digest_at,
+            path,
+            suppressed,
+        })
+    }
+
+    fn save(&self) {
+        let json = serde_json::to_string_pretty(&self.suppressed).expect("Failed to serialize");
+        if let Err(err) = fs::write(&self.path, json) {
+            error!("Failed to save digest {}: {}", self.path.display(), err);
+        }
+    }
+
+    /// Whether the run should be reported. Runs that aren't are kept for
+    /// the digest. With no rules every run is reported.
+    pub fn should_notify(&mut self, info: &CommandInfo) -> bool {
+        if self.rules.is_empty() || self.rules.iter().any(|rule| rule.matches(info)) {
+            return true;
+        }
+        info!("No notify_on rule matched, not reporting the run");
+        self.suppressed.push(Suppressed {
+            command: info.command.clone(),
+            start: info.start.into(),
+            time: info.time,
+            status: exit_status(info.status),
+        });
+        self.save();
+        false
+    }
+
+    /// When the next digest is due, if there's anything to put in it
+    pub fn next_digest(&self) -> Option<DateTime<Local>> {
+        let first = self.suppressed.first()?.start;
+        let at = Local
+            .from_local_datetime(&first.date_naive().and_time(self.digest_at))
+            .earliest()?;
+        Some(if at > first {
+            at
+        } else {
+            at + chrono::Duration::days(1)
+        })
+    }
+
+    /// Takes the digest of the suppressed runs, leaving it empty
+    pub fn take_digest(&mut self) -> Option<Digest> {
+        if self.suppressed.is_empty() {
+            return None;
+        }
+        let digest = Digest(std::mem::take(&mut self.suppressed));
+        self.save();
+        Some(digest)
+    }
+}
+
+pub struct Digest(Vec<Suppressed>);
+
+impl Display for Digest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} runs weren't reported:", self.0.len())?;
+        for run in &self.0 {
+            write!(
+                f,
+                "\n  {} \"{}\": {} after {}s",
+                run.start.format("%Y-%m-%d %H:%M"),
+                run.command,
+                run.status,
+                run.time.as_secs()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rules() {
+        assert!(matches!(Rule::parse("always"), Ok(Rule::Always)));
+        assert!(matches!(Rule::parse("success"), Ok(Rule::Success)));
+        assert!(matches!(Rule::parse(" failure "), Ok(Rule::Failure)));
+        assert!(matches!(
+            Rule::parse("duration>1h30m"),
+            Ok(Rule::LongerThan(limit)) if limit == Duration::from_secs(5400)
+        ));
+        assert!(matches!(
+            Rule::parse("duration<30s"),
+            Ok(Rule::ShorterThan(limit)) if limit == Duration::from_secs(30)
+        ));
+        assert!(matches!(
+            Rule::parse("stderr~/Traceback/"),
+            Ok(Rule::Matches(Stream::Stderr, regex)) if regex.as_str() == "Traceback"
+        ));
+        assert!(matches!(
+            Rule::parse("output~/a/b/"),
+            Ok(Rule::Matches(Stream::Output, regex)) if regex.as_str() == "a/b"
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        for rule in [
+            "",
+            "sometimes",
+            "duration=1h",
+            "duration>soon",
+            "duration>99999999999999999d",
+            "stdin~/x/",
+            "stdout~x",
+            "output~/(/",
+        ] {
+            assert!(Rule::parse(rule).is_err(), "{}", rule);
+        }
+    }
+}
@@ -0,0 +1,231 @@
+use std::{borrow::Cow, fs::File, io, io::Write, path::Path, process::ExitStatus, time::Duration};
+
+use flate2::{write::GzEncoder, Compression};
+
+use crate::ansi::strip;
+use crate::backends::backend::Sendable;
+use crate::config::OutputLimits;
+use crate::runner::CommandInfo;
+
+/// Lines of output in the notice of a failed attempt
+const NOTICE_LINES: usize = 5;
+
+/// What's said of a run whose exit status couldn't be read
+pub const UNKNOWN_STATUS: &str = "unknown exit status";
+
+/// The exit status as text
+pub fn exit_status(status: Option<ExitStatus>) -> String {
+    match status {
+        Some(status) => status.to_string(),
+        None => UNKNOWN_STATUS.to_owned(),
+    }
+}
+
+/// Whether `text` is too long to be put in a message as is
+pub fn exceeds(text: &str, limits: &OutputLimits) -> bool {
+    text.len() > limits.max_body_size
+        || text.lines().count() > limits.head_lines + limits.tail_lines
+}
+
+/// How the command exited and the resources it used
+pub fn status_line(info: &CommandInfo) -> String {
+    let status = exit_status(info.status);
+    let mut line = match &info.usage {
+        Some(usage) => format!("{}; {}", status, usage),
+        None => status,
+    };
+    if !info.attempts.is_empty() {
+        let earlier: Vec<String> = info
+            .attempts
+            .iter()
+            .map(|attempt| {
+                format!(
+                    "{} after {}s",
+                    exit_status(attempt.status),
+                    attempt.time.as_secs()
+                )
+            })
+            .collect();
+        line += &format!(
+            " (attempt {}, earlier: {})",
+            info.attempts.len() + 1,
+            earlier.join(", ")
+        );
+    }
+    for deviation in &info.deviations {
+        line += &format!("; {}", deviation);
+    }
+    line
+}
+
+/// How the run deviated from earlier ones, for subject lines
+pub fn deviation_note(info: &CommandInfo) -> String {
+    if info.deviations.is_empty() {
+        return String::new();
+    }
+    let short: Vec<String> = info.deviations.iter().map(|d| d.short()).collect();
+    format!(" ({})", short.join(", "))
+}
+
+/// The last lines of stderr, or of stdout when nothing was written to stderr
+pub fn last_output(info: &CommandInfo) -> String {
+    let output = if info.stderr.trim().is_empty() {
+        &info.stdout
+    } else {
+        &info.stderr
+    };
+    let output = strip(output);
+    let lines: Vec<&str> = output.lines().collect();
+    lines[lines.len().saturating_sub(NOTICE_LINES)..].join("\n")
+}
+
+/// Short report of a failed attempt that's about to be retried
+pub fn attempt_notice(info: &CommandInfo, retries: u32, backoff: Duration) -> String {
+    let status = exit_status(info.status);
+    format!(
+        "Attempt {} of {} of \"{}\" failed after {}s: {}\nRetrying in {}s, last output:\n{}",
+        info.attempts.len() + 1,
+        retries + 1,
+        info.command,
+        info.time.as_secs(),
+        status,
+        backoff.as_secs(),
+        last_output(info)
+    )
+}
+
+/// Note for a stream heading when the stream had bytes that couldn't be decoded
+pub fn invalid_note(invalid: bool) -> &'static str {
+    if invalid {
+        " (invalid bytes replaced)"
+    } else {
+        ""
+    }
+}
+
+/// Cuts `text` down to its first and last lines, marking how much was left out.
+///
+/// `dropped` is the number of lines the runner already discarded before `text`.
+pub fn excerpt<'a>(text: &'a str, dropped: usize, limits: &OutputLimits) -> Cow<'a, str> {
+    if dropped > 0 {
+        return Cow::Owned(format!(
+            "... {} earlier lines not kept ...\n{}",
+            dropped,
+            excerpt(text, 0, limits)
+        ));
+    }
+    if !exceeds(text, limits) {
+        return Cow::Borrowed(text);
+    }
+
+    let lines: Vec<&str> = text.lines().collect();
+    let mut excerpt = if lines.len() > limits.head_lines + limits.tail_lines {
+        let omitted = lines.len() - limits.head_lines - limits.tail_lines;
+        format!(
+            "{}\n... {} lines omitted ...\n{}",
+            lines[..limits.head_lines].join("\n"),
+            omitted,
+            lines[lines.len() - limits.tail_lines..].join("\n")
+        )
+    } else {
+        text.to_owned()
+    };
+
+    // Very long lines can still leave us over the size limit
+    if excerpt.len() > limits.max_body_size {
+        let half = limits.max_body_size / 2;
+        let mut head_end = half;
+        while !excerpt.is_char_boundary(head_end) {
+            head_end -= 1;
+        }
+        let mut tail_start = excerpt.len() - half;
+        while !excerpt.is_char_boundary(tail_start) {
+            tail_start += 1;
+        }
+        excerpt = format!(
+            "{}\n... {} bytes omitted ...\n{}",
+            &excerpt[..head_end],
+            tail_start - head_end,
+            &excerpt[tail_start..]
+        );
+    }
+
+    Cow::Owned(excerpt)
+}
+
+pub fn compress(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+pub fn compress_file(path: &Path) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    io::copy(&mut File::open(path)?, &mut encoder)?;
+    encoder.finish()
+}
+
+/// Gzipped copies of any output that had to be cut down for the report, or
+/// that was binary and so left out of it.
+///
+/// Logs that are still over the attachment limit once compressed are replaced
+/// by a message saying so.
+pub fn full_logs(info: &CommandInfo, limits: &OutputLimits) -> Vec<Sendable> {
+    let mut logs = Vec::new();
+
+    for (stream, text, dropped, log, binary) in [
+        (
+            "stdout",
+            &info.stdout,
+            info.stdout_dropped,
+            &info.stdout_log,
+            &info.stdout_binary,
+        ),
+        (
+            "stderr",
+            &info.stderr,
+            info.stderr_dropped,
+            &info.stderr_log,
+            &info.stderr_binary,
+        ),
+    ] {
+        if !exceeds(text, limits) && dropped == 0 && binary.is_none() {
+            continue;
+        }
+        let name = match binary {
+            Some(_) => format!("{}.bin.gz", stream),
+            None => format!("{}.log.gz", stream),
+        };
+        // The log on disk has everything, the in memory copy may only be the tail
+        let data = match (log, binary) {
+            (Some(log), _) => compress_file(log),
+            (None, Some(binary)) => compress(binary),
+            (None, None) => compress(text.as_bytes()),
+        };
+        let data = match data {
+            Ok(data) => data,
+            Err(err) => {
+                logs.push(Sendable::Raw(format!(
+                    "Failed to compress {}: {}",
+                    name, err
+                )));
+                continue;
+            }
+        };
+        if data.len() > limits.max_attachment_size {
+            logs.push(Sendable::Raw(format!(
+                "{} is {} bytes, over the {} byte attachment limit",
+                name,
+                data.len(),
+                limits.max_attachment_size
+            )));
+            continue;
+        }
+        let mime = "application/gzip"
+            .parse()
+            .expect("Impossible error, bad MIME type");
+        logs.push(Sendable::File((mime, name, data)));
+    }
+
+    logs
+}
@@ -0,0 +1,204 @@
+use std::{
+    fmt::Display,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+
+use crate::ansi::strip;
+use crate::config::ProgressConfig;
+use crate::lines::{compile, LineWriter, Lines};
+
+lazy_static! {
+    /// A tqdm bar, e.g. ` 45%|████▌     | 45/100 [00:10<00:12,  4.50it/s]`
+    static ref TQDM: Regex = Regex::new(
+        r"(\d+)%\|.*\|\s*([\d.]+[kMG]?)/([\d.]+[kMG]?)\s*\[[\d:]+<([\d:]+|\?)"
+    )
+    .expect("Impossible error, failed to parse regex");
+}
+
+/// How far along the command is
+#[derive(Clone)]
+pub struct Snapshot {
+    pub done: f64,
+    pub total: f64,
+    /// The line the progress was read from
+    pub line: String,
+    /// Time left as printed by the command, if it does
+    pub reported_eta: Option<Duration>,
+    pub updated: Instant,
+}
+
+#[derive(Default)]
+struct State {
+    /// The first progress seen, the rate is measured from it
+    first: Option<(Instant, f64)>,
+    last: Option<Snapshot>,
+    /// Whether a configured pattern matched, tqdm bars are ignored from then
+    /// on as they're usually the inner loop
+    from_pattern: bool,
+}
+
+/// Progress of the running command, updated from its output
+#[derive(Clone)]
+pub struct Progress {
+    patterns: Arc<Vec<Regex>>,
+    tqdm: bool,
+    state: Arc<Mutex<State>>,
+}
+
+/// Parses numbers like `45`, `1.5` or tqdm's `12.3k`
+fn number(text: &str) -> Option<f64> {
+    let (digits, scale) = match text.chars().last()? {
+        'k' => (&text[..text.len() - 1], 1e3),
+        'M' => (&text[..text.len() - 1], 1e6),
+        'G' => (&text[..text.len() - 1], 1e9),
+        _ => (text, 1.0),
+    };
+    Some(digits.parse::<f64>().ok()? * scale)
+}
+
+/// Parses tqdm's `MM:SS` or `H:MM:SS`
+fn clock(text: &str) -> Option<Duration> {
+    let mut seconds = 0;
+    for part in text.split(':') {
+        seconds = seconds * 60 + part.parse::<u64>().ok()?;
+    }
+    Some(Duration::from_secs(seconds))
+}
+
+/// The done and total counts of a match, from the `done` and `total` groups
+/// or else the first two
+fn counts(captures: &Captures) -> Option<(f64, f64)> {
+    let done = captures.name("done").or_else(|| captures.get(1))?;
+    let total = captures.name("total").or_else(|| captures.get(2))?;
+    Some((number(done.as_str())?, number(total.as_str())?))
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m {}s", secs / 60, secs % 60),
+        _ => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+impl Progress {
+    pub fn new(config: &ProgressConfig) -> Result<Self, (String, regex::Error)> {
+        Ok(Self {
+            patterns: Arc::new(compile(&config.patterns)?),
+            tqdm: config.tqdm,
+            state: Arc::new(Mutex::new(State::default())),
+        })
+    }
+
+    /// Reads progress from a line, or only from tqdm bars when the line may
+    /// not be complete yet
+    fn update(&self, line: &str, complete: bool) {
+        let line = strip(line);
+        let from_pattern = self
+            .patterns
+            .iter()
+            .filter(|_| complete)
+            .find_map(|regex| counts(&regex.captures(&line)?))
+            .map(|(done, total)| (done, total, None));
+        let from_tqdm = || {
+            let captures = TQDM.captures(&line)?;
+            let (done, total) = (number(&captures[2])?, number(&captures[3])?);
+            Some((done, total, clock(&captures[4])))
+        };
+
+        let mut state = self.state.lock().expect("progress lock poisoned");
+        let found = match from_pattern {
+            Some(found) => {
+                state.from_pattern = true;
+                Some(found)
+            }
+            None if self.tqdm && !state.from_pattern => from_tqdm(),
+            None => None,
+        };
+        let Some((done, total, reported_eta)) = found else {
+            return;
+        };
+
+        let now = Instant::now();
+        // Counting started over, e.g. a new tqdm bar
+        if state.last.as_ref().is_some_and(|last| done < last.done) {
+            state.first = None;
+        }
+        state.first.get_or_insert((now, done));
+        state.last = Some(Snapshot {
+            done,
+            total,
+            line: line.trim().to_owned(),
+            reported_eta,
+            updated: now,
+        });
+    }
+
+    /// The last progress seen, if any
+    pub fn snapshot(&self) -> Option<Snapshot> {
+        self.state
+            .lock()
+            .expect("progress lock poisoned")
+            .last
+            .clone()
+    }
+
+    /// Time left, from the command itself or the rate since progress was first seen
+    pub fn eta(&self) -> Option<Duration> {
+        let state = self.state.lock().expect("progress lock poisoned");
+        let last = state.last.as_ref()?;
+        if last.reported_eta.is_some() {
+            return last.reported_eta;
+        }
+        let (start, first_done) = state.first?;
+        let elapsed = last.updated.duration_since(start).as_secs_f64();
+        let rate = (last.done - first_done) / elapsed;
+        if !rate.is_finite() || rate <= 0.0 {
+            return None;
+        }
+        let left =
+            ((last.total - last.done) / rate - last.updated.elapsed().as_secs_f64()).max(0.0);
+        Some(Duration::from_secs_f64(left))
+    }
+
+    /// A writer reading progress from each line of one stream, and each
+    /// redraw of a line, tqdm starts them with \r
+    pub fn stream(&self) -> LineWriter<Progress> {
+        LineWriter::new(self.clone(), b"\n\r")
+    }
+}
+
+impl Display for Progress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Some(snapshot) = self.snapshot() else {
+            return f.write_str("No progress seen yet");
+        };
+        write!(f, "{}/{}", snapshot.done, snapshot.total)?;
+        if snapshot.total > 0.0 {
+            write!(f, " ({:.1}%)", snapshot.done / snapshot.total * 100.0)?;
+        }
+        match self.eta() {
+            Some(eta) => write!(f, ", about {} left", format_duration(eta))?,
+            None => f.write_str(", time left unknown")?,
+        }
+        write!(
+            f,
+            "\nLast seen {} ago: {}",
+            format_duration(snapshot.updated.elapsed()),
+            snapshot.line
+        )
+    }
+}
+
+impl Lines for Progress {
+    fn line(&mut self, line: &[u8], complete: bool) {
+        if !line.is_empty() {
+            self.update(&String::from_utf8_lossy(line), complete);
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use std::{
+    fs::File,
+    io,
+    io::Read,
+    os::{fd::FromRawFd, unix::process::CommandExt},
+    process::Command,
+    ptr,
+};
+
+/// Opens a pseudo-terminal the size of ours, returning (master, slave).
+pub fn open() -> io::Result<(File, File)> {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    if unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } != 0
+        || size.ws_col == 0
+    {
+        size.ws_col = 120;
+        size.ws_row = 40;
+    }
+
+    let mut master = 0;
+    let mut slave = 0;
+    if unsafe { libc::openpty(&mut master, &mut slave, ptr::null_mut(), ptr::null(), &size) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // The child only needs the slave side
+    unsafe { libc::fcntl(master, libc::F_SETFD, libc::FD_CLOEXEC) };
+
+    Ok(unsafe { (File::from_raw_fd(master), File::from_raw_fd(slave)) })
+}
+
+/// Makes `slave` the child's stdout, stderr and controlling terminal.
+pub fn attach(command: &mut Command, slave: File) -> io::Result<()> {
+    command.stdout(slave.try_clone()?).stderr(slave);
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setsid() == -1 || libc::ioctl(libc::STDOUT_FILENO, libc::TIOCSCTTY, 0) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+/// Reads the master side of a PTY, ending cleanly once the child closes it.
+pub struct PtyReader(pub File);
+
+impl Read for PtyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.0.read(buf) {
+            // Linux reports EIO rather than EOF when the slave side is closed
+            Err(err) if err.raw_os_error() == Some(libc::EIO) => Ok(0),
+            res => res,
+        }
+    }
+}
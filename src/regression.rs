@@ -0,0 +1,126 @@
+use std::{collections::BTreeMap, fmt::Display};
+
+use crate::config::RegressionConfig;
+use crate::history::Record;
+use crate::runner::CommandInfo;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Measure {
+    Duration,
+    PeakMemory,
+}
+
+/// A run that took much longer or shorter, or used much more or less
+/// memory, than earlier runs of the same command
+pub struct Deviation {
+    pub measure: Measure,
+    pub value: f64,
+    /// Median over the earlier runs compared with
+    pub median: f64,
+    pub runs: usize,
+}
+
+impl Deviation {
+    /// Relative change from the median, 0.2 being 20% more
+    pub fn change(&self) -> f64 {
+        self.value / self.median - 1.0
+    }
+
+    /// Short form for subject lines, like "35% slower"
+    pub fn short(&self) -> String {
+        let percent = (self.change().abs() * 100.0).round();
+        let more = self.change() > 0.0;
+        match (self.measure, more) {
+            (Measure::Duration, true) => format!("{}% slower", percent),
+            (Measure::Duration, false) => format!("{}% faster", percent),
+            (Measure::PeakMemory, true) => format!("{}% more memory", percent),
+            (Measure::PeakMemory, false) => format!("{}% less memory", percent),
+        }
+    }
+
+    fn format_value(&self, value: f64) -> String {
+        match self.measure {
+            Measure::Duration => format!("{:.1}s", value),
+            Measure::PeakMemory => format!("{:.1} MiB", value / 1024.0),
+        }
+    }
+}
+
+impl Display for Deviation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} than the median of the last {} runs ({} vs {})",
+            self.short(),
+            self.runs,
+            self.format_value(self.value),
+            self.format_value(self.median)
+        )
+    }
+}
+
+fn median(values: &mut [f64]) -> f64 {
+    values.sort_by(f64::total_cmp);
+    let middle = values.len() / 2;
+    if values.len().is_multiple_of(2) {
+        (values[middle - 1] + values[middle]) / 2.0
+    } else {
+        values[middle]
+    }
+}
+
+/// Compares the run against the last successful runs of the same command
+/// with the same parameters, `runs` being the history before it
+pub fn compare(
+    info: &CommandInfo,
+    parameters: &BTreeMap<String, String>,
+    runs: &[Record],
+    config: &RegressionConfig,
+) -> Vec<Deviation> {
+    let earlier: Vec<&Record> = runs
+        .iter()
+        .rev()
+        .filter(|record| {
+            record.success && record.command == info.command && record.parameters == *parameters
+        })
+        .take(config.window)
+        .collect();
+
+    let mut deviations = Vec::new();
+    for (measure, threshold, value) in [
+        (
+            Measure::Duration,
+            config.duration_threshold,
+            Some(info.time.as_secs_f64()),
+        ),
+        (
+            Measure::PeakMemory,
+            config.memory_threshold,
+            info.usage.map(|usage| usage.max_rss as f64),
+        ),
+    ] {
+        let Some(value) = value else {
+            continue;
+        };
+        let mut values: Vec<f64> = earlier
+            .iter()
+            .filter_map(|record| match measure {
+                Measure::Duration => Some(record.time.as_secs_f64()),
+                Measure::PeakMemory => record.usage.map(|usage| usage.max_rss as f64),
+            })
+            .collect();
+        if values.is_empty() || values.len() < config.min_runs {
+            continue;
+        }
+        let deviation = Deviation {
+            measure,
+            value,
+            median: median(&mut values),
+            runs: values.len(),
+        };
+        if deviation.median > 0.0 && deviation.change().abs() > threshold {
+            deviations.push(deviation);
+        }
+    }
+    deviations
+}
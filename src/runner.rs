@@ -1,89 +1,477 @@
 use std::{
-    io,
-    io::Write,
-    process::Stdio,
-    time::{Duration, SystemTime},
+    borrow::Cow,
+    fmt::Display,
+    fs, io,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus, Stdio},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use crossbeam::thread;
+use crossbeam::{channel, thread};
+use encoding_rs::Encoding;
 use execute::shell;
 use thiserror::Error;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::alerts::{Alert, Watchers};
+use crate::ansi::collapse_redraws;
+use crate::capture::Capture;
+use crate::config::{CaptureConfig, Config, JobConfig};
+use crate::decode::decode;
+use crate::metrics::{Extractor, Metrics};
+use crate::progress::Progress;
+use crate::pty::{self, PtyReader};
+use crate::regression::Deviation;
+use crate::sampler::{self, kill_tree, Sample};
+use crate::usage::{self, Usage};
 
 #[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum RunnerError {
     #[error("Error running command {0}:\n {1}")]
     RuntimeError(String, String),
+    #[error("Failed to create log file {0}:\n {1}")]
+    LogError(String, String),
+    #[error("Unknown charset {0}")]
+    CharsetError(String),
+    #[error("Failed to open a pseudo-terminal:\n {0}")]
+    PtyError(String),
+    #[error("Invalid pattern {0}:\n {1}")]
+    PatternError(String, String),
+}
+
+/// What to run: a line for the shell, or a program and its arguments
+#[derive(Clone)]
+pub enum JobCommand {
+    Shell(String),
+    Argv(Vec<String>),
+}
+
+fn quote(arg: &str) -> Cow<'_, str> {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c);
+    if !arg.is_empty() && arg.chars().all(plain) {
+        Cow::Borrowed(arg)
+    } else {
+        Cow::Owned(format!("'{}'", arg.replace('\'', "'\\''")))
+    }
+}
+
+impl Display for JobCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JobCommand::Shell(line) => f.write_str(line),
+            JobCommand::Argv(argv) => {
+                let argv: Vec<Cow<'_, str>> = argv.iter().map(|arg| quote(arg)).collect();
+                f.write_str(&argv.join(" "))
+            }
+        }
+    }
+}
+
+/// Whether a flag is -c or a cluster of short flags with it like -ec, but
+/// not a long flag like --norc
+fn is_command_flag(flag: &str) -> bool {
+    match flag.strip_prefix('-') {
+        Some(letters) => letters.contains('c') && letters.chars().all(|c| c.is_ascii_alphabetic()),
+        None => false,
+    }
+}
+
+impl JobCommand {
+    fn build(&self, job: &JobConfig) -> Command {
+        let mut command = match self {
+            JobCommand::Shell(line) => {
+                let mut words = job.shell.iter().flat_map(|shell| shell.split_whitespace());
+                match words.next() {
+                    Some(program) => {
+                        let flags: Vec<&str> = words.collect();
+                        let mut command = Command::new(program);
+                        command.args(&flags);
+                        // "bash" needs -c adding, "zsh -lc" already has it
+                        if !flags.last().is_some_and(|flag| is_command_flag(flag)) {
+                            command.arg("-c");
+                        }
+                        command.arg(line);
+                        command
+                    }
+                    None => shell(line),
+                }
+            }
+            JobCommand::Argv(argv) => {
+                let mut command = Command::new(&argv[0]);
+                command.args(&argv[1..]);
+                command
+            }
+        };
+        if let Some(cwd) = &job.cwd {
+            command.current_dir(cwd);
+        }
+        command.envs(&job.env);
+        command
+    }
+}
+
+/// Lets the command be killed from elsewhere while it runs
+#[derive(Clone, Default)]
+pub struct Handle(Arc<Mutex<u32>>);
+
+impl Handle {
+    /// Kills the command and its children, false if it isn't running
+    pub fn kill(&self) -> bool {
+        // Held while killing, the command isn't reaped until it's released
+        let pid = self.0.lock().unwrap();
+        match *pid {
+            0 => false,
+            pid => {
+                kill_tree(pid);
+                true
+            }
+        }
+    }
+
+    fn set(&self, pid: u32) {
+        *self.0.lock().unwrap() = pid;
+    }
+}
+
+/// A failed run that was retried
+#[derive(Clone, Copy)]
+pub struct Attempt {
+    pub time: Duration,
+    pub status: Option<ExitStatus>,
 }
 
 pub struct CommandInfo {
+    pub start: SystemTime,
     pub time: Duration,
     pub command: String,
+    /// End of stdout, the complete stream is in `stdout_log` if one was kept
     pub stdout: String,
+    /// End of stderr, the complete stream is in `stderr_log` if one was kept
     pub stderr: String,
+    /// Lines dropped from the front of `stdout`
+    pub stdout_dropped: usize,
+    /// Lines dropped from the front of `stderr`
+    pub stderr_dropped: usize,
+    pub stdout_log: Option<PathBuf>,
+    pub stderr_log: Option<PathBuf>,
+    /// Whether invalid bytes in stdout were replaced
+    pub stdout_invalid: bool,
+    /// Whether invalid bytes in stderr were replaced
+    pub stderr_invalid: bool,
+    /// Raw stdout when it was binary rather than text
+    pub stdout_binary: Option<Vec<u8>>,
+    /// Raw stderr when it was binary rather than text
+    pub stderr_binary: Option<Vec<u8>>,
+    pub status: Option<ExitStatus>,
+    pub usage: Option<Usage>,
+    /// Resource use over the run, if sampling was enabled
+    pub samples: Vec<Sample>,
+    /// Earlier failed attempts, when the command was retried
+    pub attempts: Vec<Attempt>,
+    /// Values read from the output with the metric patterns
+    pub metrics: Metrics,
+    /// How the run differs from earlier runs of the command
+    pub deviations: Vec<Deviation>,
 }
 
 impl CommandInfo {
-    pub fn new(command: String, time: Duration, stdout: String, stderr: String) -> Self {
+    pub fn new(
+        command: String,
+        start: SystemTime,
+        time: Duration,
+        stdout: String,
+        stderr: String,
+    ) -> Self {
         Self {
+            start,
             time,
             command,
             stdout,
             stderr,
+            stdout_dropped: 0,
+            stderr_dropped: 0,
+            stdout_log: None,
+            stderr_log: None,
+            stdout_invalid: false,
+            stderr_invalid: false,
+            stdout_binary: None,
+            stderr_binary: None,
+            status: None,
+            usage: None,
+            samples: Vec::new(),
+            attempts: Vec::new(),
+            metrics: Metrics::default(),
+            deviations: Vec::new(),
+        }
+    }
+}
+
+/// Whether a run that exited with `status` should be retried, `None`
+/// meaning its status is unknown
+pub fn should_retry(job: &JobConfig, status: Option<ExitStatus>) -> bool {
+    match status {
+        Some(status) if status.success() => false,
+        Some(status) => {
+            job.retry_on_exit_codes.is_empty()
+                || status
+                    .code()
+                    .is_some_and(|code| job.retry_on_exit_codes.contains(&code))
         }
+        None => job.retry_on_exit_codes.is_empty(),
     }
 }
 
-pub fn run(command: &String) -> Result<CommandInfo, RunnerError> {
+/// Longest wait between two attempts, however many failed before
+const MAX_BACKOFF: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How long to wait before retrying after `failed` attempts
+pub fn backoff(job: &JobConfig, failed: usize) -> Duration {
+    let seconds = job.retry_backoff.max(0.0) * 2f64.powi(failed as i32);
+    Duration::try_from_secs_f64(seconds)
+        .unwrap_or(MAX_BACKOFF)
+        .min(MAX_BACKOFF)
+}
+
+fn open_capture(
+    config: &CaptureConfig,
+    start: SystemTime,
+    stream: &str,
+) -> Result<Capture, RunnerError> {
+    let log = match &config.log_dir {
+        Some(dir) => {
+            if let Err(err) = fs::create_dir_all(dir) {
+                return Err(RunnerError::LogError(dir.to_owned(), err.to_string()));
+            }
+            let millis = start
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis();
+            Some(Path::new(dir).join(format!("{}-{}.log", millis, stream)))
+        }
+        None => None,
+    };
+    let name = log
+        .as_ref()
+        .map(|path| path.display().to_string())
+        .unwrap_or_default();
+    match Capture::new(config.max_bytes, config.max_lines, log) {
+        Ok(capture) => Ok(capture),
+        Err(err) => Err(RunnerError::LogError(name, err.to_string())),
+    }
+}
+
+/// Runs the command, sending alerts for lines matching the configured
+/// watchers to `alerts`, updating `progress` as it goes and setting the
+/// pid of `handle` while it runs
+pub fn run(
+    command: &JobCommand,
+    config: &Config,
+    alerts: Option<UnboundedSender<Alert>>,
+    progress: Option<Progress>,
+    handle: Option<Handle>,
+) -> Result<CommandInfo, RunnerError> {
+    // The watchers kill through it too
+    let handle = handle.unwrap_or_default();
+    let mut watchers = match alerts {
+        Some(alerts) if !config.watchers.is_empty() => {
+            match Watchers::new(&config.watchers, alerts, command.to_string()) {
+                Ok(watchers) => Some(watchers),
+                Err((pattern, err)) => {
+                    return Err(RunnerError::PatternError(pattern, err.to_string()))
+                }
+            }
+        }
+        _ => None,
+    };
+    let metrics = match &config.metrics.patterns {
+        patterns if patterns.is_empty() => None,
+        patterns => match Extractor::new(patterns) {
+            Ok(extractor) => Some(extractor),
+            Err((pattern, err)) => return Err(RunnerError::PatternError(pattern, err.to_string())),
+        },
+    };
+    let job = &config.job;
+    let sampling = &config.sampling;
+    let config = &config.capture;
+    let charset = match &config.charset {
+        Some(label) => match Encoding::for_label(label.as_bytes()) {
+            Some(charset) => charset,
+            None => return Err(RunnerError::CharsetError(label.to_owned())),
+        },
+        None => encoding_rs::UTF_8,
+    };
+
     let start = SystemTime::now();
+    let mut stdout_capture = open_capture(config, start, "stdout")?;
+    let mut stderr_capture = open_capture(config, start, "stderr")?;
 
-    let mut binding = shell(command);
-    binding.stdout(Stdio::piped());
-    let cmd = binding.stderr(Stdio::piped());
+    let mut binding = command.build(job);
+    let master = if config.pty {
+        let pty = pty::open().and_then(|(master, slave)| {
+            pty::attach(&mut binding, slave)?;
+            Ok(master)
+        });
+        match pty {
+            Ok(master) => Some(master),
+            Err(err) => return Err(RunnerError::PtyError(err.to_string())),
+        }
+    } else {
+        binding.stdout(Stdio::piped());
+        binding.stderr(Stdio::piped());
+        None
+    };
 
-    let mut child = cmd.spawn().unwrap();
-    let mut child_stdout = child.stdout.take().expect("logic error getting stdout");
-    let mut child_stderr = child.stderr.take().expect("logic error getting stderr");
+    let child = binding.spawn();
+    // Our copy of the PTY slave has to be closed for reads of the master to end
+    drop(binding);
+    let mut child = match child {
+        Ok(child) => child,
+        Err(err) => {
+            return Err(RunnerError::RuntimeError(
+                command.to_string(),
+                err.to_string(),
+            ))
+        }
+    };
+    handle.set(child.id());
+    if let Some(watchers) = &mut watchers {
+        watchers.attach(handle.clone());
+    }
+    // Under a PTY both streams arrive together on the master side
+    let (mut child_stdout, mut child_stderr): (Box<dyn Read + Send>, Box<dyn Read + Send>) =
+        match master {
+            Some(master) => (Box::new(PtyReader(master)), Box::new(io::empty())),
+            None => (
+                Box::new(child.stdout.take().expect("logic error getting stdout")),
+                Box::new(child.stderr.take().expect("logic error getting stderr")),
+            ),
+        };
 
-    let (stdout, stderr) = thread::scope(|s| {
-        let stdout_thread = s.spawn(|_| -> Vec<u8> {
-            let stdout = io::stdout();
-            let mut stdout = stdout.lock();
-            let mut stdout_log = Vec::<u8>::new();
-            let mut tee = TeeWriter::new(&mut stdout, &mut stdout_log);
+    let (waited, samples) = thread::scope(|s| {
+        let stdout_thread = s.spawn(|_| {
+            // Locked for each write, other jobs may be writing too
+            let mut stdout = io::stdout();
+            let mut observers = observers(&watchers, &progress, &metrics);
+            let mut capture = TeeWriter::new(&mut stdout_capture, &mut observers);
+            let mut tee = TeeWriter::new(&mut stdout, &mut capture);
             io::copy(&mut child_stdout, &mut tee).unwrap();
-            stdout_log
+            tee.flush().unwrap();
         });
-        let stderr_thread = s.spawn(|_| -> Vec<u8> {
-            let stderr = io::stderr();
-            let mut stderr = stderr.lock();
-            let mut stderr_log = Vec::<u8>::new();
-            let mut tee = TeeWriter::new(&mut stderr, &mut stderr_log);
+        let stderr_thread = s.spawn(|_| {
+            let mut stderr = io::stderr();
+            let mut observers = observers(&watchers, &progress, &metrics);
+            let mut capture = TeeWriter::new(&mut stderr_capture, &mut observers);
+            let mut tee = TeeWriter::new(&mut stderr, &mut capture);
 
             io::copy(&mut child_stderr, &mut tee).unwrap();
-            stderr_log
+            tee.flush().unwrap();
         });
 
-        let _status = child.wait().expect("child wasn't running");
+        let (done_tx, done_rx) = channel::bounded(1);
+        let sampler_thread = sampling.as_ref().map(|sampling| {
+            let pid = child.id();
+            let interval = Duration::from_secs_f64(sampling.interval);
+            s.spawn(move |_| sampler::sample(pid, interval, done_rx))
+        });
 
-        let stdout_log = stdout_thread.join().expect("stdout thread panicked");
-        let stderr_log = stderr_thread.join().expect("stderr thread panicked");
+        let exited = usage::wait_exit(child.id());
+        // Cleared while the process is a zombie, its pid may be reused once
+        // it's reaped, though the watchers may still be reading its output
+        handle.set(0);
+        let waited = exited.and_then(|()| usage::wait(child.id()));
+        let _ = done_tx.send(());
 
-        (stdout_log, stderr_log)
+        stdout_thread.join().expect("stdout thread panicked");
+        stderr_thread.join().expect("stderr thread panicked");
+        let samples = match sampler_thread {
+            Some(thread) => thread.join().expect("sampler thread panicked"),
+            None => Vec::new(),
+        };
+        (waited, samples)
     })
     .expect("stdout/stderr thread panicked");
-    let stdout = String::from_utf8(stdout).unwrap();
-    let stderr = String::from_utf8(stderr).unwrap();
+    let (status, usage) = match waited {
+        Ok(waited) => waited,
+        Err(err) => {
+            return Err(RunnerError::RuntimeError(
+                command.to_string(),
+                err.to_string(),
+            ))
+        }
+    };
+    let (stdout, stdout_dropped) = stdout_capture.tail.into_inner(charset);
+    let (stderr, stderr_dropped) = stderr_capture.tail.into_inner(charset);
+    let mut stdout = decode(stdout, charset);
+    let stderr = decode(stderr, charset);
+    if config.pty {
+        stdout.text = collapse_redraws(&stdout.text);
+    }
 
-    let info = CommandInfo::new(
-        command.to_owned(),
+    let mut info = CommandInfo::new(
+        command.to_string(),
+        start,
         start.elapsed().unwrap_or_default(),
-        stdout,
-        stderr,
+        stdout.text,
+        stderr.text,
     );
+    info.stdout_invalid = stdout.invalid;
+    info.stderr_invalid = stderr.invalid;
+    info.stdout_binary = stdout.binary;
+    info.stderr_binary = stderr.binary;
+    info.status = Some(status);
+    info.usage = Some(usage);
+    info.samples = samples;
+    if let Some(metrics) = metrics {
+        info.metrics = metrics.take();
+    }
+    info.stdout_dropped = stdout_dropped;
+    info.stderr_dropped = stderr_dropped;
+    info.stdout_log = stdout_capture.log.map(|(path, _)| path);
+    info.stderr_log = stderr_capture.log.map(|(path, _)| path);
 
     Ok(info)
 }
 
+/// Everything reading a stream as it's written besides the capture
+struct Observers(Vec<Box<dyn Write>>);
+
+fn observers(
+    watchers: &Option<Watchers>,
+    progress: &Option<Progress>,
+    metrics: &Option<Extractor>,
+) -> Observers {
+    let mut observers: Vec<Box<dyn Write>> = Vec::new();
+    if let Some(watchers) = watchers {
+        observers.push(Box::new(watchers.stream()));
+    }
+    if let Some(progress) = progress {
+        observers.push(Box::new(progress.stream()));
+    }
+    if let Some(metrics) = metrics {
+        observers.push(Box::new(metrics.stream()));
+    }
+    Observers(observers)
+}
+
+impl Write for Observers {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for observer in &mut self.0 {
+            observer.write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        for observer in &mut self.0 {
+            observer.flush()?;
+        }
+        Ok(())
+    }
+}
+
 struct TeeWriter<'a, W0: Write, W1: Write> {
     w0: &'a mut W0,
     w1: &'a mut W1,
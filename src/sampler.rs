@@ -0,0 +1,220 @@
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    fs,
+    time::{Duration, Instant},
+};
+
+use crossbeam::channel::{Receiver, RecvTimeoutError};
+
+use crate::backends::backend::Sendable;
+use crate::chart::{line_chart, Panel, Series};
+
+/// Resource use of the command's process tree at one point in the run
+#[derive(Clone, Copy)]
+pub struct Sample {
+    pub elapsed: Duration,
+    /// CPU use since the last sample, 100 per busy core
+    pub cpu: f64,
+    /// Resident set size in bytes
+    pub rss: u64,
+    /// Bytes read from disk per second since the last sample
+    pub read_rate: f64,
+    /// Bytes written to disk per second since the last sample
+    pub write_rate: f64,
+}
+
+#[derive(Default)]
+struct Totals {
+    /// utime + stime + cutime + cstime, in clock ticks
+    ticks: u64,
+    rss: u64,
+    read_bytes: u64,
+    write_bytes: u64,
+}
+
+/// Fields of /proc/<pid>/stat after the command name, which may contain spaces
+pub fn stat_fields(pid: &str) -> Option<Vec<String>> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    let rest = &stat[stat.rfind(')')? + 1..];
+    Some(rest.split_whitespace().map(str::to_owned).collect())
+}
+
+/// Pids of the processes started by `root`, their children and so on
+pub fn descendants(root: u32) -> Vec<u32> {
+    let mut children: HashMap<String, Vec<String>> = HashMap::new();
+    if let Ok(entries) = fs::read_dir("/proc") {
+        for entry in entries.flatten() {
+            let pid = entry.file_name().to_string_lossy().into_owned();
+            if let Some(fields) = stat_fields(&pid) {
+                children.entry(fields[1].to_owned()).or_default().push(pid);
+            }
+        }
+    }
+
+    let mut descendants = Vec::new();
+    let mut pending = vec![root.to_string()];
+    while let Some(pid) = pending.pop() {
+        if let Some(pids) = children.remove(&pid) {
+            descendants.extend(pids.iter().filter_map(|pid| pid.parse::<u32>().ok()));
+            pending.extend(pids);
+        }
+    }
+    descendants
+}
+
+/// Terminates `root` and every process it started
+pub fn kill_tree(root: u32) {
+    // Children holding the output open would keep the run going, stop the
+    // command first so it can't start more meanwhile
+    let pid = root as libc::pid_t;
+    unsafe { libc::kill(pid, libc::SIGSTOP) };
+    for child in descendants(root) {
+        unsafe { libc::kill(child as libc::pid_t, libc::SIGTERM) };
+    }
+    unsafe {
+        libc::kill(pid, libc::SIGTERM);
+        libc::kill(pid, libc::SIGCONT);
+    }
+}
+
+fn tree_totals(root: u32) -> Totals {
+    let mut children: HashMap<String, Vec<String>> = HashMap::new();
+    let mut stats: HashMap<String, Vec<String>> = HashMap::new();
+    if let Ok(entries) = fs::read_dir("/proc") {
+        for entry in entries.flatten() {
+            let pid = entry.file_name().to_string_lossy().into_owned();
+            if !pid.bytes().all(|b| b.is_ascii_digit()) {
+                continue;
+            }
+            if let Some(fields) = stat_fields(&pid) {
+                // fields[1] is the parent pid
+                children
+                    .entry(fields[1].to_owned())
+                    .or_default()
+                    .push(pid.to_owned());
+                stats.insert(pid, fields);
+            }
+        }
+    }
+
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+    let mut totals = Totals::default();
+    let mut pending = vec![root.to_string()];
+    while let Some(pid) = pending.pop() {
+        if let Some(fields) = stats.get(&pid) {
+            let field = |i: usize| {
+                fields
+                    .get(i)
+                    .and_then(|f| f.parse::<u64>().ok())
+                    .unwrap_or(0)
+            };
+            totals.ticks += field(11) + field(12) + field(13) + field(14);
+            totals.rss += field(21) * page_size;
+        }
+        if let Ok(io) = fs::read_to_string(format!("/proc/{}/io", pid)) {
+            for line in io.lines() {
+                let (key, value) = line.split_once(": ").unwrap_or_default();
+                let value = value.parse::<u64>().unwrap_or(0);
+                match key {
+                    "read_bytes" => totals.read_bytes += value,
+                    "write_bytes" => totals.write_bytes += value,
+                    _ => {}
+                }
+            }
+        }
+        if let Some(pids) = children.remove(&pid) {
+            pending.extend(pids);
+        }
+    }
+    totals
+}
+
+/// Samples the process tree under `root` every `interval` until `done` fires.
+pub fn sample(root: u32, interval: Duration, done: Receiver<()>) -> Vec<Sample> {
+    let ticks_per_sec = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as f64;
+    let start = Instant::now();
+    let mut samples = Vec::new();
+    let mut last = (Instant::now(), tree_totals(root));
+
+    while let Err(RecvTimeoutError::Timeout) = done.recv_timeout(interval) {
+        let now = Instant::now();
+        let totals = tree_totals(root);
+        let secs = (now - last.0).as_secs_f64();
+        // Counters of children that exited are lost, so they can go backwards
+        samples.push(Sample {
+            elapsed: now - start,
+            cpu: totals.ticks.saturating_sub(last.1.ticks) as f64 / ticks_per_sec / secs * 100.0,
+            rss: totals.rss,
+            read_rate: totals.read_bytes.saturating_sub(last.1.read_bytes) as f64 / secs,
+            write_rate: totals.write_bytes.saturating_sub(last.1.write_bytes) as f64 / secs,
+        });
+        last = (now, totals);
+    }
+
+    samples
+}
+
+pub fn to_csv(samples: &[Sample]) -> String {
+    let mut csv = "elapsed_s,cpu_percent,rss_bytes,read_bytes_per_s,write_bytes_per_s\n".to_owned();
+    for sample in samples {
+        let _ = writeln!(
+            csv,
+            "{:.3},{:.1},{},{:.0},{:.0}",
+            sample.elapsed.as_secs_f64(),
+            sample.cpu,
+            sample.rss,
+            sample.read_rate,
+            sample.write_rate
+        );
+    }
+    csv
+}
+
+fn series(name: &str, samples: &[Sample], value: impl Fn(&Sample) -> f64) -> Series {
+    Series {
+        name: name.to_owned(),
+        points: samples
+            .iter()
+            .map(|sample| (sample.elapsed.as_secs_f64(), value(sample)))
+            .collect(),
+    }
+}
+
+/// A chart of the samples and the samples themselves as CSV
+pub fn attachments(samples: &[Sample]) -> Vec<Sendable> {
+    const MIB: f64 = 1024.0 * 1024.0;
+    let panels = [
+        Panel {
+            title: "CPU (%)".to_owned(),
+            series: vec![series("CPU", samples, |s| s.cpu)],
+        },
+        Panel {
+            title: "RSS (MiB)".to_owned(),
+            series: vec![series("RSS", samples, |s| s.rss as f64 / MIB)],
+        },
+        Panel {
+            title: "Disk I/O (MiB/s)".to_owned(),
+            series: vec![
+                series("read", samples, |s| s.read_rate / MIB),
+                series("write", samples, |s| s.write_rate / MIB),
+            ],
+        },
+    ];
+
+    let mut attachments = Vec::new();
+    match line_chart("seconds", &panels) {
+        Ok(png) => attachments.push(Sendable::Image((
+            mime::IMAGE_PNG,
+            "resources.png".to_owned(),
+            png,
+        ))),
+        Err(err) => attachments.push(Sendable::Raw(err.to_string())),
+    }
+    attachments.push(Sendable::File((
+        mime::TEXT_CSV,
+        "resources.csv".to_owned(),
+        to_csv(samples).into_bytes(),
+    )));
+    attachments
+}
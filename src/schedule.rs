@@ -0,0 +1,285 @@
+use std::{fmt::Display, fs, io, path::PathBuf, str::FromStr, time::Duration};
+
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone};
+use log::*;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::config::ScheduleConfig;
+
+#[derive(Error, Debug)]
+pub enum ScheduleError {
+    #[error("Invalid cron expression {0}:\n {1}")]
+    CronError(String, String),
+    #[error("Failed to read schedule {0}:\n {1}")]
+    LoadError(String, String),
+}
+
+/// A one-off run asked for by the user
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Pending {
+    pub id: u32,
+    pub at: DateTime<Local>,
+}
+
+/// Runs waiting to happen, the one-off ones are kept in a file so they
+/// survive restarts
+pub struct Schedule {
+    pending: Vec<Pending>,
+    path: PathBuf,
+    cron: Option<(String, cron::Schedule)>,
+    next_cron: Option<DateTime<Local>>,
+}
+
+/// Parses the `03:00`, `2023-07-01 03:00` or `in 2h30m` of a rerun command,
+/// times already past aren't accepted
+pub fn parse_when(text: &str) -> Option<DateTime<Local>> {
+    let now = Local::now();
+    let (kind, rest) = text.trim().split_once(' ')?;
+    let rest = rest.trim();
+    match kind {
+        "at" => {
+            if let Ok(time) = NaiveTime::parse_from_str(rest, "%H:%M") {
+                let today = now.date_naive().and_time(time);
+                let at = Local.from_local_datetime(&today).earliest()?;
+                return Some(if at > now {
+                    at
+                } else {
+                    at + chrono::Duration::days(1)
+                });
+            }
+            let at = NaiveDateTime::parse_from_str(rest, "%Y-%m-%d %H:%M")
+                .ok()
+                .or_else(|| {
+                    let date = NaiveDate::parse_from_str(rest, "%Y-%m-%d").ok()?;
+                    Some(date.and_time(NaiveTime::MIN))
+                })?;
+            Local
+                .from_local_datetime(&at)
+                .earliest()
+                .filter(|at| *at > now)
+        }
+        "in" => {
+            let duration = parse_duration(rest)?;
+            now.checked_add_signed(chrono::Duration::from_std(duration).ok()?)
+        }
+        _ => None,
+    }
+}
+
+/// Parses durations like `90s`, `15m` or `1h30m`
+pub fn parse_duration(text: &str) -> Option<Duration> {
+    let mut total = 0u64;
+    let mut number = String::new();
+    for c in text.chars().filter(|c| !c.is_whitespace()) {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            _ => return None,
+        };
+        let seconds = number.parse::<u64>().ok()?.checked_mul(unit)?;
+        total = total.checked_add(seconds)?;
+        number.clear();
+    }
+    if !number.is_empty() || total == 0 {
+        return None;
+    }
+    Some(Duration::from_secs(total))
+}
+
+impl Schedule {
+    pub fn load(config: &ScheduleConfig) -> Result<Self, ScheduleError> {
+        let cron = match &config.cron {
+            Some(expression) => {
+                // The cron crate wants seconds, accept the usual five fields too
+                let full = if expression.split_whitespace().count() == 5 {
+                    format!("0 {}", expression)
+                } else {
+                    expression.to_owned()
+                };
+                match cron::Schedule::from_str(&full) {
+                    Ok(schedule) => Some((expression.to_owned(), schedule)),
+                    Err(err) => {
+                        return Err(ScheduleError::CronError(
+                            expression.to_owned(),
+                            err.to_string(),
+                        ))
+                    }
+                }
+            }
+            None => None,
+        };
+
+        let path = PathBuf::from(&config.file);
+        let pending = match fs::read_to_string(&path) {
+            Ok(text) => match serde_json::from_str(&text) {
+                Ok(pending) => pending,
+                Err(err) => {
+                    return Err(ScheduleError::LoadError(
+                        config.file.clone(),
+                        err.to_string(),
+                    ))
+                }
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => {
+                return Err(ScheduleError::LoadError(
+                    config.file.clone(),
+                    err.to_string(),
+                ))
+            }
+        };
+
+        let mut schedule = Self {
+            pending,
+            path,
+            cron,
+            next_cron: None,
+        };
+        schedule.advance_cron();
+        Ok(schedule)
+    }
+
+    fn save(&self) {
+        let json = serde_json::to_string_pretty(&self.pending).expect("Failed to serialize");
+        if let Err(err) = fs::write(&self.path, json) {
+            error!("Failed to save schedule {}: {}", self.path.display(), err);
+        }
+    }
+
+    fn advance_cron(&mut self) {
+        self.next_cron = self
+            .cron
+            .as_ref()
+            .and_then(|(_, schedule)| schedule.upcoming(Local).next());
+    }
+
+    pub fn add(&mut self, at: DateTime<Local>) -> u32 {
+        let id = self.pending.iter().map(|p| p.id).max().unwrap_or(0) + 1;
+        self.pending.push(Pending { id, at });
+        self.pending.sort_by_key(|p| p.at);
+        self.save();
+        id
+    }
+
+    /// Cancels one run, or every one-off run when `id` is `None`. Returns
+    /// how many were cancelled.
+    pub fn cancel(&mut self, id: Option<u32>) -> usize {
+        let before = self.pending.len();
+        self.pending.retain(|p| id.is_some_and(|id| p.id != id));
+        self.save();
+        before - self.pending.len()
+    }
+
+    /// When the next run is due, if any is
+    pub fn next(&self) -> Option<DateTime<Local>> {
+        let next_pending = self.pending.first().map(|p| p.at);
+        match (next_pending, self.next_cron) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Removes every run that's due, several due at once make one run
+    pub fn take_due(&mut self) -> bool {
+        let now = Local::now();
+        let before = self.pending.len();
+        self.pending.retain(|p| p.at > now);
+        let mut due = self.pending.len() != before;
+        if due {
+            self.save();
+        }
+        if self.next_cron.is_some_and(|at| at <= now) {
+            self.advance_cron();
+            due = true;
+        }
+        due
+    }
+}
+
+/// Sleeps until `at`, or forever when there's nothing scheduled
+pub async fn sleep_until(at: Option<DateTime<Local>>) {
+    match at {
+        Some(at) => {
+            let wait = (at - Local::now()).to_std().unwrap_or_default();
+            tokio::time::sleep(wait).await
+        }
+        None => std::future::pending().await,
+    }
+}
+
+impl Display for Schedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.pending.is_empty() && self.cron.is_none() {
+            return f.write_str("No runs scheduled");
+        }
+        f.write_str("Scheduled runs:")?;
+        for pending in &self.pending {
+            write!(
+                f,
+                "\n  {}: {}",
+                pending.id,
+                pending.at.format("%Y-%m-%d %H:%M")
+            )?;
+        }
+        if let Some((expression, _)) = &self.cron {
+            write!(f, "\n  cron \"{}\"", expression)?;
+            if let Some(next) = self.next_cron {
+                write!(f, ", next at {}", next.format("%Y-%m-%d %H:%M"))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("90s"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("1h30m"), Some(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("1d 2h"), Some(Duration::from_secs(93600)));
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        for text in ["", "0s", "15", "m", "5x", "-5m"] {
+            assert_eq!(parse_duration(text), None, "{}", text);
+        }
+    }
+
+    #[test]
+    fn rejects_overflowing_durations() {
+        assert_eq!(parse_duration("99999999999999999d"), None);
+        assert_eq!(parse_duration("18446744073709551615s1s"), None);
+        assert_eq!(parse_when("in 18446744073709551615s"), None);
+    }
+
+    #[test]
+    fn parses_times() {
+        let now = Local::now();
+        let at = parse_when("at 03:00").unwrap();
+        assert!(at > now && at - now <= chrono::Duration::days(1));
+        let at = parse_when("in 2h30m").unwrap();
+        let error = at - now - chrono::Duration::minutes(150);
+        assert!(error.num_seconds().abs() < 5);
+        let at = parse_when("at 2999-01-01 12:00").unwrap();
+        assert_eq!(at.format("%Y-%m-%d %H:%M").to_string(), "2999-01-01 12:00");
+        let at = parse_when("at 2999-01-01").unwrap();
+        assert_eq!(at.format("%Y-%m-%d %H:%M").to_string(), "2999-01-01 00:00");
+    }
+
+    #[test]
+    fn rejects_past_times() {
+        assert_eq!(parse_when("at 2000-01-01 03:00"), None);
+        assert_eq!(parse_when("at 2000-01-01"), None);
+    }
+}
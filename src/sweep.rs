@@ -0,0 +1,261 @@
+use std::{collections::BTreeMap, path::Path, time::Duration};
+
+use regex::Regex;
+use thiserror::Error;
+
+use crate::ansi::strip;
+use crate::backends::backend::Sendable;
+use crate::config::{Config, SweepConfig, TableConfig};
+use crate::output::{exit_status, last_output};
+use crate::runner::{CommandInfo, JobCommand, RunnerError};
+use crate::table::Table;
+
+#[derive(Error, Debug)]
+pub enum SweepError {
+    #[error("No sweep section in the config")]
+    NotConfigured,
+    #[error("Sweep parameter {0} has no values")]
+    EmptyParameter(String),
+    #[error("Invalid metric pattern {0}:\n {1}")]
+    PatternError(String, String),
+}
+
+/// Value of each parameter for one run
+pub type Point = BTreeMap<String, String>;
+
+fn format_point(point: &Point) -> String {
+    let values: Vec<String> = point
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect();
+    values.join(" ")
+}
+
+/// The runs of a sweep and how to read their metric
+pub struct Sweep {
+    pub points: Vec<Point>,
+    names: Vec<String>,
+    metric: Option<Regex>,
+    minimize: bool,
+}
+
+impl Sweep {
+    pub fn new(config: Option<&SweepConfig>) -> Result<Self, SweepError> {
+        let Some(config) = config else {
+            return Err(SweepError::NotConfigured);
+        };
+        let metric = match &config.metric {
+            Some(pattern) => match Regex::new(pattern) {
+                Ok(regex) => Some(regex),
+                Err(err) => return Err(SweepError::PatternError(pattern.clone(), err.to_string())),
+            },
+            None => None,
+        };
+
+        // Every combination of values, the last parameter changing fastest
+        let mut points = vec![Point::new()];
+        for (name, values) in &config.parameters {
+            if values.is_empty() {
+                return Err(SweepError::EmptyParameter(name.clone()));
+            }
+            points = points
+                .into_iter()
+                .flat_map(|point| {
+                    values.iter().map(move |value| {
+                        let mut point = point.clone();
+                        let value = match value {
+                            toml::Value::String(value) => value.clone(),
+                            value => value.to_string(),
+                        };
+                        point.insert(name.clone(), value);
+                        point
+                    })
+                })
+                .collect();
+        }
+        Ok(Self {
+            points,
+            names: config.parameters.keys().cloned().collect(),
+            metric,
+            minimize: config.minimize,
+        })
+    }
+
+    /// The command with `{name}` replaced by the value of each parameter
+    pub fn command(command: &JobCommand, point: &Point) -> JobCommand {
+        let substitute = |text: &str| {
+            point.iter().fold(text.to_owned(), |text, (name, value)| {
+                text.replace(&format!("{{{}}}", name), value)
+            })
+        };
+        match command {
+            JobCommand::Shell(line) => JobCommand::Shell(substitute(line)),
+            JobCommand::Argv(argv) => {
+                JobCommand::Argv(argv.iter().map(|arg| substitute(arg)).collect())
+            }
+        }
+    }
+
+    /// The config to run the `index`th point with, logging to a directory of
+    /// its own as runs started in the same millisecond share log names
+    pub fn config(config: &Config, index: usize, point: &Point) -> Config {
+        let mut config = config.clone();
+        config.job.env.extend(point.clone());
+        if let Some(dir) = &config.capture.log_dir {
+            let name: String = format_point(point)
+                .chars()
+                .map(|c| match c {
+                    'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '=' | '-' => c,
+                    _ => '_',
+                })
+                .collect();
+            let dir = Path::new(dir).join(format!("{}_{}", index + 1, name));
+            config.capture.log_dir = Some(dir.display().to_string());
+        }
+        config
+    }
+
+    /// The metric from the last line of stdout it's on
+    fn metric(&self, info: &CommandInfo) -> Option<f64> {
+        let regex = self.metric.as_ref()?;
+        let stdout = strip(&info.stdout);
+        let captures = regex.captures_iter(&stdout).last()?;
+        let value = captures.name("value").or_else(|| captures.get(1))?;
+        value.as_str().parse().ok()
+    }
+
+    /// The results of the runs so far
+    pub fn results(&self, command: String) -> Results {
+        Results {
+            command,
+            names: self.names.clone(),
+            has_metric: self.metric.is_some(),
+            minimize: self.minimize,
+            outcomes: Vec::new(),
+        }
+    }
+
+    /// Adds a finished run to the results
+    pub fn add(&self, results: &mut Results, point: Point, info: &CommandInfo) {
+        results.outcomes.push(Outcome {
+            point,
+            metric: self.metric(info),
+            status: exit_status(info.status),
+            success: info.status.is_some_and(|status| status.success()),
+            time: info.time,
+        });
+    }
+
+    /// Adds a run that failed to start to the results
+    pub fn add_error(&self, results: &mut Results, point: Point, err: &RunnerError) {
+        results.outcomes.push(Outcome {
+            point,
+            metric: None,
+            // One line, to fit in a cell
+            status: err
+                .to_string()
+                .lines()
+                .map(str::trim)
+                .collect::<Vec<_>>()
+                .join(" "),
+            success: false,
+            time: Duration::ZERO,
+        });
+    }
+}
+
+/// Short report of a run of the sweep that failed
+pub fn failure_notice(point: &Point, info: &CommandInfo) -> String {
+    let status = exit_status(info.status);
+    format!(
+        "Sweep run {} of \"{}\" failed after {}s: {}\nLast output:\n{}",
+        format_point(point),
+        info.command,
+        info.time.as_secs(),
+        status,
+        last_output(info)
+    )
+}
+
+/// Short report of a run of the sweep that couldn't run
+pub fn error_notice(point: &Point, err: &RunnerError) -> String {
+    format!("Sweep run {} failed to start: {}", format_point(point), err)
+}
+
+struct Outcome {
+    point: Point,
+    metric: Option<f64>,
+    status: String,
+    success: bool,
+    time: Duration,
+}
+
+pub struct Results {
+    command: String,
+    names: Vec<String>,
+    has_metric: bool,
+    minimize: bool,
+    outcomes: Vec<Outcome>,
+}
+
+impl Results {
+    pub fn len(&self) -> usize {
+        self.outcomes.len()
+    }
+
+    /// The runs best first, runs without a metric last
+    fn sorted(&self) -> Vec<&Outcome> {
+        let mut sorted: Vec<&Outcome> = self.outcomes.iter().collect();
+        sorted.sort_by(|a, b| match (a.metric, b.metric) {
+            (Some(a), Some(b)) if self.minimize => a.total_cmp(&b),
+            (Some(a), Some(b)) => b.total_cmp(&a),
+            (a, b) => b.is_some().cmp(&a.is_some()),
+        });
+        sorted
+    }
+
+    pub fn to_csv(&self) -> String {
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        let mut header = self.names.clone();
+        if self.has_metric {
+            header.push("metric".to_owned());
+        }
+        header.extend(["status".to_owned(), "seconds".to_owned()]);
+        // Writing to memory can't fail
+        let _ = writer.write_record(&header);
+        for outcome in self.sorted() {
+            let mut row: Vec<String> = self
+                .names
+                .iter()
+                .map(|name| outcome.point.get(name).cloned().unwrap_or_default())
+                .collect();
+            if self.has_metric {
+                row.push(outcome.metric.map(|m| m.to_string()).unwrap_or_default());
+            }
+            row.push(outcome.status.clone());
+            row.push(format!("{:.1}", outcome.time.as_secs_f64()));
+            let _ = writer.write_record(&row);
+        }
+        String::from_utf8(writer.into_inner().unwrap_or_default()).unwrap_or_default()
+    }
+
+    /// The summary table, with the results also attached as CSV
+    pub fn report(&self, tables: &TableConfig) -> Vec<Sendable> {
+        let failed = self.outcomes.iter().filter(|o| !o.success).count();
+        let mut name = format!("Sweep of \"{}\": {} runs", self.command, self.len());
+        if failed > 0 {
+            name += &format!(", {} failed", failed);
+        }
+        let csv = self.to_csv().into_bytes();
+        let mut report = match Table::parse(name.clone(), &csv, tables) {
+            Some(table) => vec![Sendable::Table(table)],
+            None => vec![Sendable::Raw(format!("{}, results in sweep.csv", name))],
+        };
+        report.push(Sendable::File((
+            mime::TEXT_CSV,
+            "sweep.csv".to_owned(),
+            csv,
+        )));
+        report
+    }
+}
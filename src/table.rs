@@ -0,0 +1,149 @@
+use crate::ansi::escape_html;
+use crate::config::TableConfig;
+
+/// A CSV file to show inline, possibly with rows left out of the middle
+pub struct Table {
+    pub name: String,
+    pub header: Vec<String>,
+    /// Rows from the start of the file
+    pub head: Vec<Vec<String>>,
+    /// Rows left out between `head` and `tail`
+    pub omitted: usize,
+    /// Rows from the end of the file
+    pub tail: Vec<Vec<String>>,
+}
+
+fn is_number(cell: &str) -> bool {
+    !cell.is_empty() && cell.parse::<f64>().is_ok()
+}
+
+impl Table {
+    /// Parses `data` as CSV with a header row, `None` if it's too big or
+    /// isn't CSV
+    pub fn parse(name: String, data: &[u8], config: &TableConfig) -> Option<Self> {
+        if data.len() > config.max_size {
+            return None;
+        }
+        let mut reader = csv::ReaderBuilder::new()
+            .flexible(true)
+            .trim(csv::Trim::All)
+            .from_reader(data);
+        let header: Vec<String> = reader.headers().ok()?.iter().map(str::to_owned).collect();
+        let mut rows = Vec::new();
+        for record in reader.records() {
+            rows.push(record.ok()?.iter().map(str::to_owned).collect::<Vec<_>>());
+        }
+
+        let omitted = rows
+            .len()
+            .saturating_sub(config.head_rows + config.tail_rows);
+        let tail = if omitted > 0 {
+            rows.split_off(rows.len() - config.tail_rows)
+        } else {
+            Vec::new()
+        };
+        rows.truncate(rows.len() - omitted);
+        Some(Self {
+            name,
+            header,
+            head: rows,
+            omitted,
+            tail,
+        })
+    }
+
+    /// The width of each column, leaving out empty columns at the end
+    /// like the one a trailing comma makes
+    fn widths(&self) -> Vec<usize> {
+        let mut widths = Vec::new();
+        for row in [&self.header]
+            .into_iter()
+            .chain(&self.head)
+            .chain(&self.tail)
+        {
+            if widths.len() < row.len() {
+                widths.resize(row.len(), 0);
+            }
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+        while widths.last() == Some(&0) {
+            widths.pop();
+        }
+        widths
+    }
+
+    /// The table aligned in columns, for monospace text
+    pub fn to_text(&self) -> String {
+        let widths = self.widths();
+        let line = |row: &Vec<String>| {
+            let cells: Vec<String> = (0..widths.len())
+                .map(|i| {
+                    let text = row.get(i).map(String::as_str).unwrap_or("");
+                    if is_number(text) {
+                        format!("{:>width$}", text, width = widths[i])
+                    } else {
+                        format!("{:<width$}", text, width = widths[i])
+                    }
+                })
+                .collect();
+            cells.join(" | ").trim_end().to_owned()
+        };
+        let mut text = line(&self.header);
+        text.push('\n');
+        let rule: Vec<String> = widths.iter().map(|width| "-".repeat(*width)).collect();
+        text.push_str(&rule.join("-+-"));
+        for row in &self.head {
+            text.push('\n');
+            text.push_str(&line(row));
+        }
+        if self.omitted > 0 {
+            text.push_str(&format!("\n... {} rows omitted ...", self.omitted));
+        }
+        for row in &self.tail {
+            text.push('\n');
+            text.push_str(&line(row));
+        }
+        text
+    }
+
+    pub fn to_html(&self) -> String {
+        let columns = self.widths().len();
+        let row_html = |row: &Vec<String>, tag: &str| {
+            let cells: String = (0..columns)
+                .map(|i| {
+                    let text = row.get(i).map(String::as_str).unwrap_or("");
+                    let align = if is_number(text) {
+                        " align=\"right\""
+                    } else {
+                        ""
+                    };
+                    format!("<{}{}>{}</{}>", tag, align, escape_html(text), tag)
+                })
+                .collect();
+            format!("<tr>{}</tr>", cells)
+        };
+
+        let mut html = format!(
+            "<p><b>{}</b></p><table border=\"1\"><thead>{}</thead><tbody>",
+            escape_html(&self.name),
+            row_html(&self.header, "th")
+        );
+        for row in &self.head {
+            html.push_str(&row_html(row, "td"));
+        }
+        if self.omitted > 0 {
+            html.push_str(&format!(
+                "<tr><td colspan=\"{}\"><i>... {} rows omitted ...</i></td></tr>",
+                columns.max(1),
+                self.omitted
+            ));
+        }
+        for row in &self.tail {
+            html.push_str(&row_html(row, "td"));
+        }
+        html.push_str("</tbody></table>");
+        html
+    }
+}
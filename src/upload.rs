@@ -0,0 +1,81 @@
+use std::{fmt::Display, fs, io, path::Path};
+
+use log::*;
+
+use crate::config::UploadConfig;
+
+pub struct UploadReport {
+    pub directory: String,
+    pub saved: Vec<(String, usize)>,
+    pub rejected: Vec<(String, String)>,
+}
+
+impl UploadReport {
+    pub fn any_saved(&self) -> bool {
+        !self.saved.is_empty()
+    }
+}
+
+impl Display for UploadReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.any_saved() {
+            writeln!(f, "Saved to {}:", self.directory)?;
+            for (name, size) in &self.saved {
+                writeln!(f, "  {} ({} bytes)", name, size)?;
+            }
+        }
+        if !self.rejected.is_empty() {
+            writeln!(f, "Rejected:")?;
+            for (name, reason) in &self.rejected {
+                writeln!(f, "  {} ({})", name, reason)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Saves attachments sent by the user into the configured upload directory.
+///
+/// Only the final component of each name is used so an attachment can't be
+/// written outside of the directory.
+pub fn save(config: &UploadConfig, files: &[(String, Vec<u8>)]) -> io::Result<UploadReport> {
+    fs::create_dir_all(&config.directory)?;
+
+    let mut report = UploadReport {
+        directory: config.directory.to_owned(),
+        saved: Vec::new(),
+        rejected: Vec::new(),
+    };
+
+    for (name, data) in files {
+        let file_name = match Path::new(name).file_name() {
+            Some(file_name) => file_name,
+            None => {
+                report
+                    .rejected
+                    .push((name.to_owned(), "invalid file name".to_owned()));
+                continue;
+            }
+        };
+        if data.len() > config.max_size {
+            report.rejected.push((
+                name.to_owned(),
+                format!(
+                    "{} bytes is over the {} byte limit",
+                    data.len(),
+                    config.max_size
+                ),
+            ));
+            continue;
+        }
+
+        let path = Path::new(&config.directory).join(file_name);
+        fs::write(&path, data)?;
+        info!("Saved upload {}", path.display());
+        report
+            .saved
+            .push((file_name.to_string_lossy().into_owned(), data.len()));
+    }
+
+    Ok(report)
+}
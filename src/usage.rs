@@ -0,0 +1,89 @@
+use std::{
+    fmt::Display, io, os::unix::process::ExitStatusExt, process::ExitStatus, time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Resources used by a command and the children it waited for
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Usage {
+    pub user: Duration,
+    pub system: Duration,
+    /// Peak resident set size in KiB
+    pub max_rss: u64,
+    pub voluntary_switches: u64,
+    pub involuntary_switches: u64,
+    /// Blocks read from disk
+    pub block_in: u64,
+    /// Blocks written to disk
+    pub block_out: u64,
+}
+
+fn duration(time: libc::timeval) -> Duration {
+    Duration::from_secs(time.tv_sec as u64) + Duration::from_micros(time.tv_usec as u64)
+}
+
+impl From<libc::rusage> for Usage {
+    fn from(usage: libc::rusage) -> Self {
+        Self {
+            user: duration(usage.ru_utime),
+            system: duration(usage.ru_stime),
+            max_rss: usage.ru_maxrss as u64,
+            voluntary_switches: usage.ru_nvcsw as u64,
+            involuntary_switches: usage.ru_nivcsw as u64,
+            block_in: usage.ru_inblock as u64,
+            block_out: usage.ru_oublock as u64,
+        }
+    }
+}
+
+impl Display for Usage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "CPU {:.2}s user, {:.2}s system; peak RSS {:.1} MiB; \
+             {} voluntary/{} involuntary context switches; {} blocks in/{} out",
+            self.user.as_secs_f64(),
+            self.system.as_secs_f64(),
+            self.max_rss as f64 / 1024.0,
+            self.voluntary_switches,
+            self.involuntary_switches,
+            self.block_in,
+            self.block_out
+        )
+    }
+}
+
+/// Waits for the process `pid` to exit without reaping it, so its pid
+/// isn't reused until `wait` is called
+pub fn wait_exit(pid: u32) -> io::Result<()> {
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    loop {
+        let flags = libc::WEXITED | libc::WNOWAIT;
+        if unsafe { libc::waitid(libc::P_PID, pid as libc::id_t, &mut info, flags) } != -1 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+/// Waits for the process `pid` to exit, returning its status and usage.
+///
+/// Like `Child::wait` but through `wait4` so the usage of the process and
+/// every descendant it reaped can be read.
+pub fn wait(pid: u32) -> io::Result<(ExitStatus, Usage)> {
+    let mut status = 0;
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    loop {
+        if unsafe { libc::wait4(pid as libc::pid_t, &mut status, 0, &mut usage) } != -1 {
+            return Ok((ExitStatus::from_raw(status), usage.into()));
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
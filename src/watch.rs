@@ -0,0 +1,212 @@
+use std::{
+    collections::HashSet,
+    fs, io,
+    os::unix::process::ExitStatusExt,
+    process::ExitStatus,
+    time::{Duration, SystemTime},
+};
+
+use log::*;
+use thiserror::Error;
+
+use crate::runner::CommandInfo;
+use crate::sampler::stat_fields;
+use crate::usage::Usage;
+
+/// `idtype_t` for waiting on a pidfd, missing from libc
+const P_PIDFD: libc::idtype_t = 3;
+
+#[derive(Error, Debug)]
+pub enum WatchError {
+    #[error("No process matching {0}")]
+    NotFound(String),
+    #[error("Several processes match {0}:\n{1}")]
+    Ambiguous(String, String),
+}
+
+fn cmdline(pid: u32) -> Option<String> {
+    let cmdline = fs::read(format!("/proc/{}/cmdline", pid)).ok()?;
+    let args: Vec<String> = cmdline
+        .split(|b| *b == 0)
+        .filter(|arg| !arg.is_empty())
+        .map(|arg| String::from_utf8_lossy(arg).into_owned())
+        .collect();
+    Some(args.join(" "))
+}
+
+/// Our own pid and those of the processes that started us, which would
+/// otherwise match `--name` since it's in our command line
+fn ancestors() -> HashSet<u32> {
+    let mut ancestors = HashSet::new();
+    let mut pid = std::process::id();
+    while pid > 1 && ancestors.insert(pid) {
+        pid = match stat_fields(&pid.to_string()).and_then(|fields| fields[1].parse().ok()) {
+            Some(parent) => parent,
+            None => break,
+        };
+    }
+    ancestors
+}
+
+/// Finds the process to watch, returning its pid and command line
+pub fn find(pid: Option<u32>, name: Option<&str>) -> Result<(u32, String), WatchError> {
+    if let Some(pid) = pid {
+        return match cmdline(pid) {
+            Some(cmdline) => Ok((pid, cmdline)),
+            None => Err(WatchError::NotFound(format!("pid {}", pid))),
+        };
+    }
+    let name = name.unwrap_or_default();
+
+    let ignore = ancestors();
+    let mut matches = Vec::new();
+    if let Ok(entries) = fs::read_dir("/proc") {
+        for entry in entries.flatten() {
+            let Ok(pid) = entry.file_name().to_string_lossy().parse::<u32>() else {
+                continue;
+            };
+            if ignore.contains(&pid) {
+                continue;
+            }
+            if let Some(cmdline) = cmdline(pid) {
+                if cmdline.contains(name) {
+                    matches.push((pid, cmdline));
+                }
+            }
+        }
+    }
+
+    match matches.len() {
+        0 => Err(WatchError::NotFound(name.to_owned())),
+        1 => Ok(matches.remove(0)),
+        _ => {
+            let list: Vec<String> = matches
+                .iter()
+                .map(|(pid, cmdline)| format!("  {} {}", pid, cmdline))
+                .collect();
+            Err(WatchError::Ambiguous(name.to_owned(), list.join("\n")))
+        }
+    }
+}
+
+/// What /proc last said about the process, as it's gone once it exits
+fn read_usage(pid: u32, usage: &mut Usage) {
+    let ticks_per_sec = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as f64;
+    if let Some(fields) = stat_fields(&pid.to_string()) {
+        let field = |i: usize| {
+            fields
+                .get(i)
+                .and_then(|f| f.parse::<u64>().ok())
+                .unwrap_or(0)
+        };
+        usage.user = Duration::from_secs_f64((field(11) + field(13)) as f64 / ticks_per_sec);
+        usage.system = Duration::from_secs_f64((field(12) + field(14)) as f64 / ticks_per_sec);
+    }
+    let status = fs::read_to_string(format!("/proc/{}/status", pid)).unwrap_or_default();
+    let io = fs::read_to_string(format!("/proc/{}/io", pid)).unwrap_or_default();
+    for line in status.lines().chain(io.lines()) {
+        let (key, value) = line.split_once(':').unwrap_or_default();
+        let value = value
+            .split_whitespace()
+            .next()
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(0);
+        match key {
+            "VmHWM" => usage.max_rss = value,
+            "voluntary_ctxt_switches" => usage.voluntary_switches = value,
+            "nonvoluntary_ctxt_switches" => usage.involuntary_switches = value,
+            "read_bytes" => usage.block_in = value / 512,
+            "write_bytes" => usage.block_out = value / 512,
+            _ => {}
+        }
+    }
+}
+
+/// The last `lines` lines of each log file
+fn tails(logs: &[String], lines: usize) -> String {
+    let mut tails = String::new();
+    for log in logs {
+        tails += &format!("==> {} <==\n", log);
+        match fs::read(log) {
+            Ok(data) => {
+                let text = String::from_utf8_lossy(&data);
+                let all: Vec<&str> = text.lines().collect();
+                tails += &all[all.len().saturating_sub(lines)..].join("\n");
+                tails += "\n";
+            }
+            Err(err) => tails += &format!("Failed to read log: {}\n", err),
+        }
+    }
+    tails
+}
+
+/// Blocks until `pid` exits, polling /proc for its resource use meanwhile.
+///
+/// The exit status can only be read when we're the parent, otherwise it's
+/// left unknown.
+pub fn watch(
+    pid: u32,
+    command: String,
+    logs: &[String],
+    lines: usize,
+) -> Result<CommandInfo, WatchError> {
+    let start = SystemTime::now();
+    let mut usage = Usage::default();
+    let mut status = None;
+
+    let pidfd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) } as i32;
+    if pidfd < 0 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() == Some(libc::ESRCH) {
+            return Err(WatchError::NotFound(format!("pid {}", pid)));
+        }
+        warn!("pidfd_open failed ({}), polling /proc instead", err);
+    }
+
+    loop {
+        read_usage(pid, &mut usage);
+        if pidfd >= 0 {
+            let mut poll = libc::pollfd {
+                fd: pidfd,
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            // The pidfd becomes readable when the process exits
+            if unsafe { libc::poll(&mut poll, 1, 1000) } > 0 {
+                let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+                let waited =
+                    unsafe { libc::waitid(P_PIDFD, pidfd as libc::id_t, &mut info, libc::WEXITED) };
+                if waited == 0 {
+                    let code = unsafe { info.si_status() };
+                    status = Some(match info.si_code {
+                        libc::CLD_EXITED => ExitStatus::from_raw(code << 8),
+                        _ => ExitStatus::from_raw(code),
+                    });
+                } else {
+                    debug!("Can't read exit status: {}", io::Error::last_os_error());
+                }
+                unsafe { libc::close(pidfd) };
+                break;
+            }
+        } else {
+            if unsafe { libc::kill(pid as libc::pid_t, 0) } != 0
+                && io::Error::last_os_error().raw_os_error() == Some(libc::ESRCH)
+            {
+                break;
+            }
+            std::thread::sleep(Duration::from_secs(1));
+        }
+    }
+    info!("Process {} exited", pid);
+
+    let mut info = CommandInfo::new(
+        command,
+        start,
+        start.elapsed().unwrap_or_default(),
+        tails(logs, lines),
+        String::new(),
+    );
+    info.status = status;
+    info.usage = Some(usage);
+    Ok(info)
+}